@@ -0,0 +1,121 @@
+use crate::{config::Config, header::PACK_FORMAT_VERSION, pack::PackingError};
+use std::{collections::{BTreeMap, BTreeSet}, io::Read, path::{Path, PathBuf}, time::UNIX_EPOCH};
+use futures_util::{StreamExt, TryStreamExt, stream};
+use sha2::Digest;
+
+/// A file that an addon was packed from.
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct SourceFile {
+	pub len: u64,
+	/// Seconds and nanoseconds since the Unix epoch, if the platform supports it.
+	pub modified: Option<(u64, u32)>,
+	pub sha256: String
+}
+
+/// Records what the last pack was made from and what it wrote, so that the next pack can skip anything that hasn't changed.
+///
+/// This is written to the root of the output directory (or the addon, when packing in-place). It's a hidden file, so it's never copied or packed.
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct PackCache {
+	/// A hash of the gluapack version, pack format, config and options the pack was made with.
+	pub fingerprint: String,
+	/// Every file the pack was made from, relative to the addon.
+	pub sources: BTreeMap<String, SourceFile>,
+	/// The files that were copied to the output directory as they were, relative to it.
+	pub copied: BTreeSet<String>,
+	/// The files that gluapack wrote to the output directory, relative to it.
+	pub packed: BTreeSet<String>,
+	pub unique_id: String,
+	pub unpacked_files: usize,
+	pub packed_files: usize
+}
+impl PackCache {
+	pub const FILE_NAME: &'static str = ".gluapack-cache.json";
+
+	/// Hashes everything that affects what a pack looks like, other than the addon's files.
+	pub fn fingerprint(config: &Config, in_place: bool, no_copy: bool) -> String {
+		let mut sha256 = sha2::Sha256::new();
+		sha256.update(env!("CARGO_PKG_VERSION"));
+		sha256.update(PACK_FORMAT_VERSION.to_le_bytes());
+		sha256.update(serde_json::to_vec(config).expect("Failed to serialize config"));
+		sha256.update([in_place as u8, no_copy as u8]);
+		format!("{:x}", sha256.finalize())
+	}
+
+	/// Reads the cache from a previous pack. A missing or unreadable cache just means everything is packed from scratch.
+	pub fn read(out_dir: &Path) -> Option<PackCache> {
+		let cache = std::fs::read(out_dir.join(PackCache::FILE_NAME)).ok()?;
+		serde_json::from_slice(&cache).ok()
+	}
+
+	pub async fn write(&self, out_dir: &Path) -> Result<(), PackingError> {
+		tokio::fs::write(out_dir.join(PackCache::FILE_NAME), serde_json::to_vec(self).expect("Failed to serialize pack cache")).await?;
+		Ok(())
+	}
+
+	/// Deletes the cache, so that a pack that fails halfway through isn't mistaken for an up-to-date one.
+	pub async fn invalidate(out_dir: &Path) -> Result<(), PackingError> {
+		match tokio::fs::remove_file(out_dir.join(PackCache::FILE_NAME)).await {
+			Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error.into()),
+			_ => Ok(())
+		}
+	}
+
+	/// Whether a source file had the same contents when this cache was written.
+	pub fn is_source_unchanged(&self, path: &str, sources: &BTreeMap<String, SourceFile>) -> bool {
+		match (self.sources.get(path), sources.get(path)) {
+			(Some(cached), Some(source)) => cached.sha256 == source.sha256,
+			_ => false
+		}
+	}
+
+	/// Whether the addon is made of the same files, with the same contents, as when this cache was written.
+	pub fn is_unchanged(&self, sources: &BTreeMap<String, SourceFile>) -> bool {
+		self.sources.len() == sources.len() && self.sources.keys().all(|path| self.is_source_unchanged(path, sources))
+	}
+
+	/// Whether everything the last pack wrote is still in the output directory.
+	pub fn outputs_exist(&self, out_dir: &Path) -> bool {
+		self.copied.iter().chain(self.packed.iter()).all(|path| out_dir.join(path).is_file())
+	}
+
+	/// Hashes the files an addon is packed from, `jobs` at a time.
+	///
+	/// Files with the same size and modification time as in `previous` aren't read again.
+	pub async fn hash_sources(files: Vec<(String, PathBuf)>, previous: Option<&PackCache>, jobs: usize) -> Result<BTreeMap<String, SourceFile>, PackingError> {
+		let files = files.into_iter().collect::<BTreeMap<_, _>>();
+
+		stream::iter(files)
+			.map(|(path, fs_path)| async move {
+				let metadata = tokio::fs::metadata(&fs_path).await?;
+				let len = metadata.len();
+				let modified = metadata.modified().ok()
+					.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+					.map(|modified| (modified.as_secs(), modified.subsec_nanos()));
+
+				if let Some(cached) = previous.and_then(|previous| previous.sources.get(&path)) {
+					if modified.is_some() && cached.modified == modified && cached.len == len {
+						return Ok((path, cached.clone()));
+					}
+				}
+
+				let sha256 = tokio::task::spawn_blocking(move || -> Result<String, std::io::Error> {
+					let mut f = std::fs::File::open(fs_path)?;
+					let mut sha256 = sha2::Sha256::new();
+					let mut block = vec![0; 64 * 1024];
+					loop {
+						match f.read(&mut block)? {
+							0 => break,
+							n => sha256.update(&block[..n])
+						}
+					}
+					Ok(format!("{:x}", sha256.finalize()))
+				}).await.map_err(std::io::Error::from)??;
+
+				Ok::<_, PackingError>((path, SourceFile { len, modified, sha256 }))
+			})
+			.buffer_unordered(jobs)
+			.try_collect()
+			.await
+	}
+}
@@ -0,0 +1,167 @@
+//! Networked chunks are wrapped in a single Lua long comment (`--[==[ ... ]==]`), so that the client can `CompileFile` them
+//! to get them into the Lua cache without running anything.
+
+/// The long comment level that chunks are sized for.
+///
+/// Chunks that need a deeper level than this are split until they fit. See [`commented_len`].
+pub const RESERVED_LEVEL: usize = 8;
+
+/// The number of bytes that commenting a chunk at [`RESERVED_LEVEL`] adds to it.
+pub const RESERVED_OVERHEAD: usize = overhead(RESERVED_LEVEL);
+
+/// The number of bytes that commenting a chunk at `level` adds to it: `--[`, `=` * level, `[\n`, and `]`, `=` * level, `]`.
+const fn overhead(level: usize) -> usize {
+	3 + level + 2 + 1 + level + 1
+}
+
+fn close_bracket(level: usize) -> Vec<u8> {
+	let mut close = Vec::with_capacity(level + 2);
+	close.push(b']');
+	close.resize(level + 1, b'=');
+	close.push(b']');
+	close
+}
+
+/// Finds the lowest long bracket level whose closing bracket only appears at the very end of `bytes` once it's appended.
+///
+/// The closing bracket has to be checked after it's been appended, as the end of `bytes` could be the start of a closing bracket too (e.g. `]=` followed by `=]`).
+pub fn level(bytes: &[u8]) -> usize {
+	let mut commented = Vec::with_capacity(bytes.len() + RESERVED_LEVEL + 2);
+	let mut level = 0;
+	loop {
+		let close = close_bracket(level);
+
+		commented.clear();
+		commented.extend_from_slice(bytes);
+		commented.extend_from_slice(&close);
+
+		if commented.windows(close.len()).position(|window| window == close.as_slice()) == Some(bytes.len()) {
+			return level;
+		}
+
+		level += 1;
+	}
+}
+
+/// Returns the size of `bytes` once commented by [`commentify`].
+pub fn commented_len(bytes: &[u8]) -> usize {
+	bytes.len() + overhead(level(bytes))
+}
+
+/// Wraps the byte vector in a Lua long comment.
+///
+/// The opening long bracket is followed by a newline, so that the chunk's first line is kept on its own line.
+pub fn commentify(bytes: &[u8]) -> Vec<u8> {
+	let level = level(bytes);
+
+	let mut commented = Vec::with_capacity(bytes.len() + overhead(level));
+	commented.extend_from_slice(b"--[");
+	commented.resize(commented.len() + level, b'=');
+	commented.extend_from_slice(b"[\n");
+	commented.extend_from_slice(bytes);
+	commented.extend_from_slice(&close_bracket(level));
+	commented
+}
+
+/// Unwraps a chunk that was commented by [`commentify`], returning `None` if it isn't wrapped in a long comment.
+pub fn uncommentify(commented: &[u8]) -> Option<&[u8]> {
+	let commented = commented.strip_prefix(b"--[")?;
+	let level = commented.iter().take_while(|byte| **byte == b'=').count();
+	let commented = commented[level..].strip_prefix(b"[\n")?;
+	commented.strip_suffix(close_bracket(level).as_slice())
+}
+
+/// Checks that `chunk` only contains Lua whitespace and comments, so compiling it will always produce a function that does nothing.
+///
+/// This follows the rules of LuaJIT's lexer, but anything it doesn't understand is treated as code.
+pub fn is_inert(chunk: &[u8]) -> bool {
+	let mut i = 0;
+	while i < chunk.len() {
+		match chunk[i] {
+			b' ' | b'\t' | b'\n' | b'\r' | 0x0B | 0x0C => i += 1,
+
+			b'-' if chunk.get(i + 1) == Some(&b'-') => {
+				i += 2;
+
+				// Long comment?
+				if chunk.get(i) == Some(&b'[') {
+					let level = chunk[i + 1..].iter().take_while(|byte| **byte == b'=').count();
+					if chunk.get(i + 1 + level) == Some(&b'[') {
+						i += level + 2;
+
+						let close = close_bracket(level);
+						match chunk[i..].windows(close.len()).position(|window| window == close.as_slice()) {
+							Some(pos) => {
+								i += pos + close.len();
+								continue;
+							},
+
+							// An unfinished long comment is a syntax error
+							None => return false
+						}
+					}
+				}
+
+				// Short comment
+				while i < chunk.len() && chunk[i] != b'\n' {
+					i += 1;
+				}
+			},
+
+			_ => return false
+		}
+	}
+	true
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn level_avoids_closing_brackets() {
+		assert_eq!(level(b""), 0);
+		assert_eq!(level(b"print('hi')"), 0);
+		assert_eq!(level(b"local s = [[ x ]]"), 1);
+		assert_eq!(level(b"local s = [[ x ]] .. [=[ y ]=]"), 2);
+		assert_eq!(level(b"local s = [==[ ]] ]=] ]==] ]===]"), 4);
+
+		// The end of the chunk could run into the closing bracket
+		assert_eq!(level(b"x]"), 1);
+		assert_eq!(level(b"x]="), 0);
+		assert_eq!(level(b"x]=]"), 2);
+	}
+
+	#[test]
+	fn commentify_round_trips() {
+		for bytes in [
+			&b""[..],
+			b"print('hi')\n",
+			b"local s = [[ ]] ]=] ]==]",
+			b"]==]",
+			b"]]",
+			b"x]",
+			b"x]=",
+			b"--[==[ nested ]==]",
+			b"\x01\x02\x03\x01"
+		] {
+			let commented = commentify(bytes);
+			assert_eq!(commented.len(), commented_len(bytes));
+			assert!(is_inert(&commented), "{:?}", String::from_utf8_lossy(&commented));
+			assert_eq!(uncommentify(&commented), Some(bytes));
+		}
+	}
+
+	#[test]
+	fn is_inert_rejects_code() {
+		assert!(is_inert(b""));
+		assert!(is_inert(b" \t\r\n-- comment\n--[[ long\n comment ]] --[==[ ]] ]==]\n"));
+		assert!(is_inert(b"-- comment without a newline"));
+
+		assert!(!is_inert(b"print('hi')"));
+		assert!(!is_inert(b"--[[ closed early ]] print('hi') ]]"));
+		assert!(!is_inert(b"--[==[ unfinished ]=]"));
+		assert!(!is_inert(b"-[[ not a comment ]]"));
+		assert!(!is_inert(b"--[==[ ]==]x"));
+	}
+}
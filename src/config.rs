@@ -1,8 +1,8 @@
-use std::{fs::File, path::Path};
+use std::path::{Path, PathBuf};
 
 use serde::de::{Unexpected, Visitor};
 
-use crate::pack::PackingError;
+use crate::{pack::PackingError, util, MAX_LUA_SIZE, MIN_LUA_SIZE};
 
 macro_rules! impl_default {
 	{ Config { $($field:ident: $ty:ty = $default:expr),* } } => {
@@ -92,6 +92,68 @@ impl serde::Serialize for GlobPattern {
     }
 }
 
+/// Turns JSON with comments (`//` and `/* */`) and trailing commas into plain JSON.
+///
+/// Comments and trailing commas are replaced with spaces rather than removed, so that line and column numbers in parse errors still point at the right place in the config file.
+fn strip_jsonc(mut json: Vec<u8>) -> Vec<u8> {
+	let mut i = 0;
+	let mut trailing_comma = None;
+	while i < json.len() {
+		match json[i] {
+			b'"' => {
+				trailing_comma = None;
+				i += 1;
+				while i < json.len() && json[i] != b'"' {
+					if json[i] == b'\\' {
+						i += 1;
+					}
+					i += 1;
+				}
+			},
+
+			b'/' if json.get(i + 1) == Some(&b'/') => {
+				while i < json.len() && json[i] != b'\n' {
+					json[i] = b' ';
+					i += 1;
+				}
+				continue;
+			},
+
+			b'/' if json.get(i + 1) == Some(&b'*') => {
+				json[i] = b' ';
+				json[i + 1] = b' ';
+				i += 2;
+				while i < json.len() && !(json[i] == b'*' && json.get(i + 1) == Some(&b'/')) {
+					if !json[i].is_ascii_whitespace() {
+						json[i] = b' ';
+					}
+					i += 1;
+				}
+				if i < json.len() {
+					json[i] = b' ';
+					json[i + 1] = b' ';
+					i += 2;
+				}
+				continue;
+			},
+
+			b',' => trailing_comma = Some(i),
+
+			b'}' | b']' => {
+				if let Some(comma) = trailing_comma.take() {
+					json[comma] = b' ';
+				}
+			},
+
+			byte if byte.is_ascii_whitespace() => {},
+
+			_ => trailing_comma = None
+		}
+		i += 1;
+	}
+	json
+}
+
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
 pub struct Config {
 	#[serde(default = "include_sh")]
@@ -117,11 +179,151 @@ pub struct Config {
 
 	#[serde(default)]
 	pub unique_id: Option<String>,
+
+	#[serde(default = "compress_networked")]
+	pub compress_networked: bool,
+
+	#[serde(default = "compress_sv")]
+	pub compress_sv: bool,
+
+	#[serde(default = "chunk_size")]
+	pub chunk_size: usize,
+
+	#[serde(default)]
+	pub max_chunks_cl: Option<usize>,
+
+	#[serde(default)]
+	pub max_chunks_sh: Option<usize>,
+
+	/// The base config this config inherits from, resolved to a path.
+	#[serde(default)]
+	pub extends: Option<PathBuf>,
 }
 impl Config {
+	/// The directory in the user's home directory where base configs can be shared between addons.
+	pub const USER_DIR: &'static str = ".gluapack";
+
+	/// The config file names gluapack looks for in the root of an addon.
+	pub const FILE_NAMES: &'static [&'static str] = &["gluapack.json", "gluapack.toml", "gluapack.yaml", "gluapack.yml"];
+
+	/// Finds the config file in the root of an addon, if there is one.
+	pub fn discover(dir: &Path) -> Result<Option<PathBuf>, PackingError> {
+		let found: Vec<&str> = Config::FILE_NAMES.iter().copied().filter(|file_name| dir.join(file_name).is_file()).collect();
+		if found.len() > 1 {
+			return Err(error!(PackingError::ConfigConflict(found.join(", "))));
+		}
+		Ok(found.first().map(|file_name| dir.join(file_name)))
+	}
+
+	/// Reads a config file, along with any base configs it `extends`.
 	pub fn read<P: AsRef<Path>>(path: P) -> Result<Config, PackingError> {
-		let mut f = File::open(path)?;
-		Ok(serde_json::from_reader(&mut f)?)
+		let mut layers = Vec::new();
+		let mut path = util::canonicalize(&path.as_ref().to_path_buf());
+		loop {
+			if layers.iter().any(|(layer_path, _): &(PathBuf, RawConfig)| *layer_path == path) {
+				let chain = layers.iter().map(|(layer_path, _)| layer_path.display().to_string()).chain(std::iter::once(path.display().to_string()));
+				return Err(error!(PackingError::ConfigExtendsError(format!("Config files extend each other in a loop: {}", chain.collect::<Vec<_>>().join(" -> ")))));
+			}
+
+			let layer: RawConfig = Config::parse(&path)?;
+			if let Some(unique_id) = &layer.unique_id {
+				Config::validate_unique_id(unique_id)?;
+			}
+			let base = match &layer.extends {
+				Some(extends) => Some(Config::resolve_extends(&path, extends)?),
+				None => None
+			};
+			layers.push((path, layer));
+
+			match base {
+				Some(base) => path = base,
+				None => break
+			}
+		}
+
+		let mut config = Config {
+			extends: layers.get(1).map(|(base, _)| base.clone()),
+			..Default::default()
+		};
+		for (_, layer) in layers.into_iter().rev() {
+			layer.apply(&mut config);
+		}
+
+		Config::validate_chunk_size(config.chunk_size)?;
+
+		Ok(config)
+	}
+
+	/// Finds a config file and every base config it `extends`, so they can be watched for changes.
+	///
+	/// Unlike [`Config::read`], this never fails. It stops at the first config that can't be parsed or whose base can't be found, but that config is still included, so fixing it is noticed.
+	pub fn files(path: &Path) -> Vec<PathBuf> {
+		let mut files = vec![path.to_path_buf()];
+		let mut seen = vec![util::canonicalize(&path.to_path_buf())];
+		let mut path = path.to_path_buf();
+		while let Some(base) = Config::parse::<RawConfig>(&path).ok().and_then(|layer| Config::resolve_extends(&path, layer.extends.as_deref()?).ok()) {
+			// Loops are reported by Config::read
+			if seen.contains(&base) {
+				break;
+			}
+			seen.push(base.clone());
+			files.push(base.clone());
+			path = base;
+		}
+		files
+	}
+
+	/// The unique ID is used in directory and file names, and the loader finds itself with a `*_gluapack_*` glob, so it must be a non-empty alphanumeric ASCII string.
+	pub fn validate_unique_id(unique_id: &str) -> Result<(), PackingError> {
+		if unique_id.is_empty() || !unique_id.bytes().all(|byte| byte.is_ascii_alphanumeric()) {
+			return Err(error!(PackingError::InvalidUniqueId(unique_id.to_string())));
+		}
+		Ok(())
+	}
+
+	/// Chunks can't be larger than Garry's Mod will network, and must have room for a pack header and the long comment they're wrapped in.
+	pub fn validate_chunk_size(chunk_size: usize) -> Result<(), PackingError> {
+		if !(MIN_LUA_SIZE..=MAX_LUA_SIZE).contains(&chunk_size) {
+			return Err(error!(PackingError::InvalidChunkSize(chunk_size)));
+		}
+		Ok(())
+	}
+
+	/// Parses a single config file. Errors name the file, as it may be a base config rather than the addon's own.
+	fn parse<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, PackingError> {
+		let contents = std::fs::read(path)?;
+		let path = path.to_path_buf();
+		match path.extension().and_then(|extension| extension.to_str()) {
+			Some("toml") => toml::from_slice(&contents).map_err(|error| error!(PackingError::TomlConfigError { path, error })),
+			Some("yaml") | Some("yml") => serde_yaml::from_slice(&contents).map_err(|error| error!(PackingError::YamlConfigError { path, error })),
+			_ => serde_json::from_slice(&strip_jsonc(contents)).map_err(|error| error!(PackingError::ConfigError { path, error }))
+		}
+	}
+
+	/// Finds the base config that `extends` refers to.
+	///
+	/// Relative paths are resolved relative to the directory of the config file that extends them first, and then relative to the user-wide config directory (`~/.gluapack`).
+	fn resolve_extends(config_path: &Path, extends: &str) -> Result<PathBuf, PackingError> {
+		let extends = Path::new(extends);
+		let candidates = if extends.is_absolute() {
+			vec![extends.to_path_buf()]
+		} else {
+			let mut candidates = vec![config_path.parent().unwrap_or_else(|| Path::new("")).join(extends)];
+			if let Some(home_dir) = util::home_dir() {
+				candidates.push(home_dir.join(Config::USER_DIR).join(extends));
+			}
+			candidates
+		};
+
+		match candidates.iter().find(|candidate| candidate.is_file()) {
+			Some(base) => Ok(util::canonicalize(base)),
+			None => Err(error!(PackingError::ConfigExtendsError(format!(
+				"{} extends {}, which couldn't be found. Looked in:\n{}",
+				config_path.display(),
+				extends.display(),
+				candidates.iter().map(|candidate| format!("  {}", candidate.display())).collect::<Vec<_>>().join("\n")
+			))))
+		}
 	}
 
 	pub fn dump_json(&self) {
@@ -139,6 +341,255 @@ impl_default! {
 		entry_sh: Vec<GlobPattern> = vec![GlobPattern::new("autorun/*.lua")],
 		entry_sv: Vec<GlobPattern> = vec![GlobPattern::new("autorun/server/*.lua")],
 
-		unique_id: Option<String> = None
+		unique_id: Option<String> = None,
+		compress_networked: bool = false,
+		compress_sv: bool = false,
+		chunk_size: usize = MAX_LUA_SIZE,
+		max_chunks_cl: Option<usize> = None,
+		max_chunks_sh: Option<usize> = None,
+		extends: Option<PathBuf> = None
+	}
+}
+
+/// A list in a config file that either replaces the inherited list, or is merged into it.
+///
+/// `["a", "b"]` replaces the inherited list, and `{ "prepend": ["a"], "append": ["b"] }` is merged into it.
+enum ListLayer {
+	Replace(Vec<GlobPattern>),
+	Merge {
+		prepend: Vec<GlobPattern>,
+		append: Vec<GlobPattern>
+	}
+}
+
+struct ListLayerVisitor;
+impl<'de> Visitor<'de> for ListLayerVisitor {
+	type Value = ListLayer;
+
+	fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+		formatter.write_str("a list of glob patterns, or an object with \"prepend\" and/or \"append\" lists of glob patterns")
+	}
+
+	fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+	where
+		A: serde::de::SeqAccess<'de>
+	{
+		let mut list = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+		while let Some(pattern) = seq.next_element()? {
+			list.push(pattern);
+		}
+		Ok(ListLayer::Replace(list))
+	}
+
+	fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+	where
+		A: serde::de::MapAccess<'de>
+	{
+		let mut prepend = Vec::new();
+		let mut append = Vec::new();
+		while let Some(key) = map.next_key::<String>()? {
+			match key.as_str() {
+				"prepend" => prepend = map.next_value()?,
+				"append" => append = map.next_value()?,
+				_ => return Err(serde::de::Error::unknown_field(&key, &["prepend", "append"]))
+			}
+		}
+		Ok(ListLayer::Merge { prepend, append })
+	}
+}
+
+impl<'de> serde::Deserialize<'de> for ListLayer {
+	fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+	where
+		D: serde::Deserializer<'de>
+	{
+		deserializer.deserialize_any(ListLayerVisitor)
+	}
+}
+
+impl ListLayer {
+	fn apply(self, list: &mut Vec<GlobPattern>) {
+		match self {
+			ListLayer::Replace(replace) => *list = replace,
+			ListLayer::Merge { mut prepend, append } => {
+				prepend.append(list);
+				prepend.extend(append);
+				*list = prepend;
+			}
+		}
+	}
+}
+
+/// A single config file, before it is merged with the configs it extends (or the default config).
+///
+/// Fields that are missing are inherited.
+#[derive(serde::Deserialize)]
+struct RawConfig {
+	include_sh: Option<ListLayer>,
+	include_cl: Option<ListLayer>,
+	include_sv: Option<ListLayer>,
+	exclude: Option<ListLayer>,
+
+	entry_cl: Option<ListLayer>,
+	entry_sh: Option<ListLayer>,
+	entry_sv: Option<ListLayer>,
+
+	unique_id: Option<String>,
+	compress_networked: Option<bool>,
+	compress_sv: Option<bool>,
+	chunk_size: Option<usize>,
+	max_chunks_cl: Option<usize>,
+	max_chunks_sh: Option<usize>,
+	extends: Option<String>
+}
+impl RawConfig {
+	fn apply(self, config: &mut Config) {
+		macro_rules! apply_lists {
+			($($field:ident),*) => {
+				$(
+					if let Some(layer) = self.$field {
+						layer.apply(&mut config.$field);
+					}
+				)*
+			};
+		}
+		apply_lists!(include_sh, include_cl, include_sv, exclude, entry_cl, entry_sh, entry_sv);
+
+		if self.unique_id.is_some() {
+			config.unique_id = self.unique_id;
+		}
+		if let Some(compress_networked) = self.compress_networked {
+			config.compress_networked = compress_networked;
+		}
+		if let Some(compress_sv) = self.compress_sv {
+			config.compress_sv = compress_sv;
+		}
+		if let Some(chunk_size) = self.chunk_size {
+			config.chunk_size = chunk_size;
+		}
+		if self.max_chunks_cl.is_some() {
+			config.max_chunks_cl = self.max_chunks_cl;
+		}
+		if self.max_chunks_sh.is_some() {
+			config.max_chunks_sh = self.max_chunks_sh;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Writes config files to a fresh directory, returning it.
+	fn configs(name: &str, files: &[(&str, &str)]) -> util::TempDir {
+		let dir = util::TempDir::new(name);
+		for (file_name, contents) in files {
+			std::fs::write(dir.join(file_name), contents).unwrap();
+		}
+		dir
+	}
+
+	#[test]
+	fn files_follows_extends() {
+		let dir = configs("files", &[
+			("gluapack.json", r#"{"extends": "team.toml"}"#),
+			("team.toml", r#"extends = "base.yaml""#),
+			("base.yaml", "extends: gluapack.json"),
+			("broken.json", r#"{"extends": "base.yaml""#)
+		]);
+
+		// Loops stop where they started
+		assert_eq!(Config::files(&dir.join("gluapack.json")), [dir.join("gluapack.json"), dir.join("team.toml"), dir.join("base.yaml")]);
+
+		// Configs that can't be parsed are still watched
+		assert_eq!(Config::files(&dir.join("broken.json")), [dir.join("broken.json")]);
+	}
+
+	fn patterns(list: &[GlobPattern]) -> Vec<&str> {
+		list.iter().map(|pattern| pattern.as_str()).collect()
+	}
+
+	#[test]
+	fn strip_jsonc_keeps_positions() {
+		let jsonc = "{\n\t// comment \"with a string\"\n\t\"a\": \"// not a comment\", /* block\n comment */ \"b\": [1, 2,],\n\t\"c\": \"\\\" /* not a comment */\",\n}";
+		let stripped = strip_jsonc(jsonc.as_bytes().to_vec());
+
+		// Only comments and trailing commas are blanked, so every line and column stays where it was
+		assert_eq!(stripped.len(), jsonc.len());
+		assert!(stripped.iter().zip(jsonc.bytes()).all(|(stripped, original)| *stripped == original || *stripped == b' '), "{:?}", String::from_utf8_lossy(&stripped));
+		assert_eq!(
+			String::from_utf8(stripped.clone()).unwrap().lines().map(|line| line.len()).collect::<Vec<_>>(),
+			jsonc.lines().map(|line| line.len()).collect::<Vec<_>>()
+		);
+
+		let value: serde_json::Value = serde_json::from_slice(&stripped).unwrap();
+		assert_eq!(value, serde_json::json!({ "a": "// not a comment", "b": [1, 2], "c": "\" /* not a comment */" }));
+
+		// Commas that aren't trailing are kept
+		assert_eq!(strip_jsonc(b"[1, /* x */ 2]".to_vec()), b"[1,         2]");
+	}
+
+	#[test]
+	fn list_layers() {
+		let dir = configs("list-layers", &[
+			("base.json", r#"{ "exclude": ["base/*.lua"], "entry_sv": ["base/sv_*.lua"] }"#),
+			("gluapack.json", r#"{
+				"extends": "base.json",
+				"exclude": { "prepend": ["first/*.lua"], "append": ["last/*.lua"] },
+				"entry_cl": { "append": ["extra/*.lua"] },
+				"entry_sv": ["replaced/*.lua"]
+			}"#)
+		]);
+
+		let config = Config::read(dir.join("gluapack.json")).unwrap();
+		assert_eq!(patterns(&config.exclude), ["first/*.lua", "base/*.lua", "last/*.lua"]);
+		assert_eq!(patterns(&config.entry_sv), ["replaced/*.lua"]);
+
+		// Without a base config, lists are merged into the defaults
+		let default = Config::default();
+		let mut entry_cl = patterns(&default.entry_cl);
+		entry_cl.push("extra/*.lua");
+		assert_eq!(patterns(&config.entry_cl), entry_cl);
+
+		// Keys that aren't set are inherited
+		assert_eq!(patterns(&config.include_sh), patterns(&default.include_sh));
+		assert_eq!(config.extends, Some(dir.join("base.json")));
+	}
+
+	#[test]
+	fn extends_loops_are_errors() {
+		let dir = configs("extends-loop", &[
+			("gluapack.json", r#"{"extends": "a.json"}"#),
+			("a.json", r#"{"extends": "b.json"}"#),
+			("b.json", r#"{"extends": "a.json"}"#),
+			("self.json", r#"{"extends": "self.json"}"#)
+		]);
+
+		for (config, chain) in [("gluapack.json", &["gluapack.json", "a.json", "b.json", "a.json"][..]), ("self.json", &["self.json", "self.json"][..])] {
+			match Config::read(dir.join(config)) {
+				Err(PackingError::ConfigExtendsError { error, .. }) => {
+					let chain = chain.iter().map(|file_name| dir.join(file_name).display().to_string()).collect::<Vec<_>>().join(" -> ");
+					assert!(error.ends_with(&chain), "{}", error);
+				},
+				result => panic!("expected an extends loop error, got {:?}", result)
+			}
+		}
+	}
+
+	#[test]
+	fn parse_errors_name_the_config() {
+		let dir = configs("parse-errors", &[
+			("gluapack.json", r#"{"extends": "base.toml"}"#),
+			("base.toml", "unique_id = "),
+			("broken.json", "{\n\t\"unique_id\": \"a\" // comment\n\t\"compress_sv\": true\n}")
+		]);
+
+		let error = Config::read(dir.join("gluapack.json")).unwrap_err().to_string();
+		assert!(error.starts_with(&format!("{} error: ", dir.join("base.toml").display())), "{}", error);
+
+		// Line and column numbers survive the comments being stripped
+		let error = Config::read(dir.join("broken.json")).unwrap_err().to_string();
+		assert!(error.starts_with(&format!("{} error: ", dir.join("broken.json").display())), "{}", error);
+		assert!(error.ends_with("line 3 column 2"), "{}", error);
 	}
 }
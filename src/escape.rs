@@ -0,0 +1,90 @@
+/// Garry's Mod truncates networked Lua files at the first NUL byte, so NUL bytes in networked chunks are escaped.
+///
+/// `\0` is written as `ESCAPE_BYTE` followed by `\x02`, and `ESCAPE_BYTE` itself is written as `ESCAPE_BYTE` followed by `\x03`.
+pub const ESCAPE_BYTE: u8 = 0x01;
+
+const ESCAPE_OFFSET: u8 = 0x02;
+
+#[inline]
+fn needs_escaping(byte: u8) -> bool {
+	byte == 0 || byte == ESCAPE_BYTE
+}
+
+/// Returns the size of `bytes` once escaped by [`escape`].
+pub fn escaped_len(bytes: &[u8]) -> usize {
+	bytes.len() + bytes.iter().filter(|byte| needs_escaping(**byte)).count()
+}
+
+/// Escapes `bytes` into `escaped`, so that it contains no NUL bytes.
+pub fn escape(escaped: &mut Vec<u8>, bytes: &[u8]) {
+	escaped.reserve(escaped_len(bytes));
+	for byte in bytes.iter().copied() {
+		if needs_escaping(byte) {
+			escaped.push(ESCAPE_BYTE);
+			escaped.push(byte + ESCAPE_OFFSET);
+		} else {
+			escaped.push(byte);
+		}
+	}
+}
+
+/// Reverses [`escape`], returning the offset of the first invalid escape sequence on failure.
+pub fn unescape(escaped: &[u8]) -> Result<Vec<u8>, usize> {
+	let mut bytes = Vec::with_capacity(escaped.len());
+	let mut iter = escaped.iter().copied().enumerate();
+	while let Some((i, byte)) = iter.next() {
+		if byte == ESCAPE_BYTE {
+			match iter.next() {
+				Some((_, escaped)) if needs_escaping(escaped.wrapping_sub(ESCAPE_OFFSET)) => bytes.push(escaped - ESCAPE_OFFSET),
+				_ => return Err(i)
+			}
+		} else {
+			bytes.push(byte);
+		}
+	}
+	Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn escaped(bytes: &[u8]) -> Vec<u8> {
+		let mut escaped = Vec::new();
+		escape(&mut escaped, bytes);
+		escaped
+	}
+
+	#[test]
+	fn escape_round_trips() {
+		for bytes in [
+			&b""[..],
+			b"print('hi')",
+			b"\0",
+			b"\x01",
+			b"\0\0\x01\x01\0",
+			b"\x01\x02\x03",
+			b"trailing escape byte \x01",
+			b"]==] ]] \0 [[ [==["
+		] {
+			let escaped = escaped(bytes);
+			assert_eq!(escaped.len(), escaped_len(bytes));
+			assert!(!escaped.contains(&0));
+			assert_eq!(unescape(&escaped), Ok(bytes.to_vec()));
+		}
+
+		assert_eq!(escaped(b"\0\x01"), b"\x01\x02\x01\x03");
+	}
+
+	#[test]
+	fn unescape_rejects_invalid_escapes() {
+		// Trailing escape byte
+		assert_eq!(unescape(b"abc\x01"), Err(3));
+		assert_eq!(unescape(b"\x01"), Err(0));
+
+		// Escape byte followed by something that was never escaped
+		assert_eq!(unescape(b"a\x01\x01"), Err(1));
+		assert_eq!(unescape(b"\x01\x02\x01a"), Err(2));
+		assert_eq!(unescape(b"\x01\x04"), Err(0));
+	}
+}
@@ -54,9 +54,10 @@ if GLUAPACK_SUCCESS == nil then
 			i = i + 1
 			local aComponent = tonumber(aIter())
 			local bComponent = tonumber(bIter())
-			if aComponent < bComponent then
+			-- Sort newest first
+			if aComponent > bComponent then
 				return true
-			elseif aComponent > bComponent then
+			elseif aComponent < bComponent then
 				return false
 			end
 		end
@@ -92,74 +93,150 @@ end
 
 local clientsideFiles = {}
 local GLUAPACK_CURRENT_CHUNK
+local GLUAPACK_CURRENT_PACK
 local GLUAPACK_IS_CHUNK_NETWORKED = CLIENT and true or nil
+local GLUAPACK_FORMAT = {PACK_FORMAT_VERSION}
 local TERMINATOR_HACK = string.byte("|")
-local function processChunk()
-	while not GLUAPACK_CURRENT_CHUNK:EndOfFile() do
-		local terminator = GLUAPACK_IS_CHUNK_NETWORKED and TERMINATOR_HACK or 0
-
-		-- Read path
-		local path = {}
-		while true do
-			local byte = GLUAPACK_CURRENT_CHUNK:ReadByte()
-			if byte == terminator then
-				break
-			else
-				path[#path + 1] = string.char(byte)
-			end
-			if GLUAPACK_CURRENT_CHUNK:EndOfFile() then
-				coroutine.yield()
-			end
+local NEWLINE = string.byte("\n")
+local function readByte()
+	if GLUAPACK_CURRENT_CHUNK:EndOfFile() then
+		coroutine.yield()
+	end
+	return GLUAPACK_CURRENT_CHUNK:ReadByte()
+end
+local function readUntil(terminator)
+	local bytes = {}
+	while true do
+		local byte = readByte()
+		if byte == terminator then
+			return table.concat(bytes)
+		else
+			bytes[#bytes + 1] = string.char(byte)
 		end
-
+	end
+end
+local function stringReader(data)
+	-- Reads a string like a File object
+	local pos = 0
+	local reader = {}
+	function reader:ReadByte()
+		pos = pos + 1
+		return data:byte(pos)
+	end
+	function reader:ReadULong()
+		local a, b, c, d = data:byte(pos + 1, pos + 4)
+		pos = pos + 4
+		return a + b * 0x100 + c * 0x10000 + d * 0x1000000
+	end
+	function reader:Read(length)
+		local bytes = data:sub(pos + 1, pos + length)
+		pos = pos + length
+		return bytes
+	end
+	function reader:Size()
+		return #data
+	end
+	function reader:Tell()
+		return pos
+	end
+	function reader:EndOfFile()
+		return pos >= #data
+	end
+	return reader
+end
+local function processEntries(entries)
+	for _ = 1, entries do
+		local path, remaining
 		if GLUAPACK_IS_CHUNK_NETWORKED then
+			-- Read the lengths of the path and the file, then the path
+			local pathLength = tonumber(readUntil(TERMINATOR_HACK), 16)
+			remaining = tonumber(readUntil(TERMINATOR_HACK), 16)
+
+			path = {}
+			for i = 1, pathLength do
+				path[i] = string.char(readByte())
+			end
 			path = table.concat(path)
+
 			clientsideFiles[path] = true
-			path = ("gluapack/vfs/%s.txt"):format(path)
 		else
-			path = ("gluapack/vfs/%s.txt"):format(table.concat(path))
+			path = readUntil(0)
+			remaining = GLUAPACK_CURRENT_CHUNK:ReadULong()
 		end
 
+		path = ("gluapack/vfs/%s.txt"):format(path)
 		file.CreateDir((path:gsub("/[^/]-$", "")))
 
-		local remaining
-		if GLUAPACK_IS_CHUNK_NETWORKED then
-			remaining = {}
-			while true do
-				local byte = GLUAPACK_CURRENT_CHUNK:ReadByte()
-				if byte == TERMINATOR_HACK then
-					break
-				else
-					remaining[#remaining + 1] = string.char(byte)
-				end
-				if GLUAPACK_CURRENT_CHUNK:EndOfFile() then
-					coroutine.yield()
-				end
-			end
-			remaining = tonumber(table.concat(remaining), 16)
-		else
-			remaining = GLUAPACK_CURRENT_CHUNK:ReadULong()
-		end
 		while true do
 			local readBytes = math.min(remaining, GLUAPACK_CURRENT_CHUNK:Size() - GLUAPACK_CURRENT_CHUNK:Tell())
 			file.Append(path, GLUAPACK_CURRENT_CHUNK:Read(readBytes))
 			remaining = remaining - readBytes
 
-			if GLUAPACK_CURRENT_CHUNK:EndOfFile() then
-				coroutine.yield()
-			elseif remaining <= 0 then
+			if remaining <= 0 then
 				assert(remaining == 0)
 				break
+			elseif GLUAPACK_CURRENT_CHUNK:EndOfFile() then
+				coroutine.yield()
+			end
+		end
+	end
+end
+local function processChunk()
+	-- Read the pack header
+	local format, version, entries, length, compression = readUntil(NEWLINE):match("^gluapack (%d+) (%S+) %S+ (%d+) (%d+) (%S+)$")
+	if tonumber(format) ~= GLUAPACK_FORMAT then
+		ErrorNoHalt(("gluapack: %s was packed by gluapack %s using pack format %s, but this loader only supports pack format %d. Please repack it with the latest version of gluapack.\n"):format(GLUAPACK_CURRENT_PACK, version or "0.3.0 or older", format or "?", GLUAPACK_FORMAT))
+		return
+	end
+
+	if compression == "lzma" then
+		-- Compressed packs have to be read in full before they can be decompressed
+		local encoded, remaining = {}, tonumber(length)
+		while true do
+			local readBytes = math.min(remaining, GLUAPACK_CURRENT_CHUNK:Size() - GLUAPACK_CURRENT_CHUNK:Tell())
+			encoded[#encoded + 1] = GLUAPACK_CURRENT_CHUNK:Read(readBytes)
+			remaining = remaining - readBytes
+
+			if remaining <= 0 then
+				break
+			else
+				coroutine.yield()
 			end
 		end
+
+		-- Networked packs are base64 encoded, the serverside pack isn't
+		local compressed = table.concat(encoded)
+		if GLUAPACK_IS_CHUNK_NETWORKED then
+			compressed = util.Base64Decode(compressed)
+		end
+
+		local decompressed = util.Decompress(compressed)
+		if not decompressed then
+			ErrorNoHalt(("gluapack: Failed to decompress %s. Please repack it with the latest version of gluapack.\n"):format(GLUAPACK_CURRENT_PACK))
+			return
+		end
+
+		-- The entries are read from the decompressed pack, then the chunk is put back so it can be closed
+		local chunk = GLUAPACK_CURRENT_CHUNK
+		GLUAPACK_CURRENT_CHUNK = stringReader(decompressed)
+		processEntries(tonumber(entries))
+		GLUAPACK_CURRENT_CHUNK = chunk
+	else
+		processEntries(tonumber(entries))
 	end
 end
 
+-- Networked chunks can't contain NUL bytes, so they are escaped
+local ESCAPES = { ["\1\2"] = "\0", ["\1\3"] = "\1" }
+local function unescape(chunk)
+	return (chunk:gsub("\1[\2\3]", ESCAPES))
+end
+
 local co, unpackChunk
 if CLIENT then
 	-- On the client, we read the chunks from the Lua cache.
 	-- To get Gmod to add the files to the Lua cache, we need to include/compile the files.
-	-- To include/compile the Lua files without creating an error, the files are commented on every new line.
+	-- To include/compile the Lua files without creating an error, each file is wrapped in a long comment (--[==[ ... ]==]).
 	-- We store their Lua cache file names (SHA1 truncated to 40 bytes) in a manifest file.
 	function unpackChunk(path, _, clientCacheManifest)
 		local index, realm = path:match("gluapack%.(%d+)%.(.-)%.lua$")
@@ -180,8 +257,10 @@ if CLIENT then
 		-- Decompress
 		chunk = util.Decompress(chunk)
 
-		-- Strip comments
-		chunk = chunk:sub(3, #chunk - 1):gsub("\n%-%-", "\n")
+		-- Strip the long comment and the trailing NUL
+		-- The opening bracket is followed by a newline, and the closing bracket is 3 bytes shorter than the opening bracket and its newline
+		local open = chunk:find("\n", 1, true)
+		chunk = unescape(chunk:sub(open + 1, #chunk - 1 - (open - 3)))
 
 		-- Write to our temp file "buffer"
 		file.Write("gluapack-temp.dat", chunk)
@@ -202,8 +281,9 @@ else
 			-- Is this a shared file? It will have comments...
 			local chunk = file_Read(path, "LUA")
 
-			-- Strip comments
-			chunk = chunk:sub(3):gsub("\n%-%-", "\n")
+			-- Strip the long comment
+			local open = chunk:find("\n", 1, true)
+			chunk = unescape(chunk:sub(open + 1, #chunk - (open - 3)))
 
 			-- Write to our temp file "buffer"
 			file.Write("gluapack-temp.dat", chunk)
@@ -252,6 +332,8 @@ local findSortedChunks do
 	end
 end
 local function gluaunpack(path)
+	GLUAPACK_CURRENT_PACK = path
+
 	local manifestPath, cacheManifest = path .. "manifest.lua"
 	if file_Exists(manifestPath, "LUA") then
 		if CLIENT then
@@ -0,0 +1,101 @@
+/// The version of the pack format.
+///
+/// This must be bumped whenever the packed format changes in a way that older loaders or unpackers can't read, along with the crate version.
+/// Only the loader from the newest version of gluapack runs on a server, and it unpacks every packed addon, so loaders that read different formats must never share a version.
+pub const PACK_FORMAT_VERSION: u32 = 4;
+
+const HEADER_MAGIC: &str = "gluapack";
+
+/// The line at the start of every realm's pack.
+///
+/// `gluapack <format version> <gluapack version> <realm> <entry count> <length> <compression>\n`
+///
+/// The length is the number of bytes that follow the header: the packed entries, or the compressed packed entries if the pack is compressed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackHeader {
+	pub format: u32,
+	pub version: String,
+	pub realm: String,
+	pub entries: usize,
+	pub length: u64,
+	pub compression: Compression
+}
+impl PackHeader {
+	pub fn new(realm: &str, entries: usize, length: u64, compression: Compression) -> PackHeader {
+		PackHeader {
+			format: PACK_FORMAT_VERSION,
+			version: env!("CARGO_PKG_VERSION").to_string(),
+			realm: realm.to_string(),
+			entries,
+			length,
+			compression
+		}
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		format!("{} {} {} {} {} {} {}\n", HEADER_MAGIC, self.format, self.version, self.realm, self.entries, self.length, self.compression.as_str()).into_bytes()
+	}
+
+	/// Parses a header line, with or without its trailing newline.
+	pub fn parse(line: &[u8]) -> Result<PackHeader, HeaderError> {
+		let line = std::str::from_utf8(line.strip_suffix(b"\n").unwrap_or(line)).map_err(|_| HeaderError::Missing)?;
+		let mut fields = line.split(' ');
+		if fields.next() != Some(HEADER_MAGIC) {
+			return Err(HeaderError::Missing);
+		}
+
+		let format = fields.next().and_then(|format| format.parse().ok()).ok_or(HeaderError::Missing)?;
+		let version = fields.next().ok_or(HeaderError::Missing)?.to_string();
+		if format != PACK_FORMAT_VERSION {
+			return Err(HeaderError::UnsupportedFormat { format, version });
+		}
+
+		let mut field = || fields.next().ok_or_else(|| HeaderError::Malformed(line.to_string()));
+		let realm = field()?.to_string();
+		let entries = field()?.parse().map_err(|_| HeaderError::Malformed(line.to_string()))?;
+		let length = field()?.parse().map_err(|_| HeaderError::Malformed(line.to_string()))?;
+		let compression = Compression::parse(field()?).ok_or_else(|| HeaderError::Malformed(line.to_string()))?;
+
+		Ok(PackHeader { format, version, realm, entries, length, compression })
+	}
+}
+
+/// How the packed entries that follow a [`PackHeader`] are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+	/// The packed entries follow the header as they are.
+	None,
+
+	/// The packed entries are LZMA compressed in the format Garry's Mod's `util.Decompress` reads.
+	///
+	/// Networked packs are then base64 encoded, so they can be cut into chunks anywhere.
+	Lzma
+}
+impl Compression {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Compression::None => "none",
+			Compression::Lzma => "lzma"
+		}
+	}
+
+	fn parse(compression: &str) -> Option<Compression> {
+		match compression {
+			"none" => Some(Compression::None),
+			"lzma" => Some(Compression::Lzma),
+			_ => None
+		}
+	}
+}
+
+#[derive(Debug)]
+pub enum HeaderError {
+	/// The pack doesn't start with a gluapack header, so it was probably made by gluapack 0.3.0 or older, from before packs had headers
+	Missing,
+
+	/// The pack was made by a version of gluapack that uses a different pack format
+	UnsupportedFormat { format: u32, version: String },
+
+	/// The header has the right format version, but its fields couldn't be parsed
+	Malformed(String)
+}
@@ -9,25 +9,54 @@ mod util;
 mod pack;
 mod unpack;
 mod config;
+mod header;
+mod comment;
+mod escape;
+mod cache;
+mod watch;
 
 use pack::Packer;
 use unpack::Unpacker;
+use watch::Watcher;
 
-/// The maximum size of a chunk.
+/// The maximum size of a chunk, and the default `chunk_size`.
 ///
 /// This should be 64 KiB as Garry's Mod will not network a Lua file larger than this.
 pub const MAX_LUA_SIZE: usize = 65535;
+
+/// The minimum `chunk_size`, so that every chunk has plenty of room for the pack header and its long comment.
+pub const MIN_LUA_SIZE: usize = 1024;
+/// The default number of files that are read, copied or deleted at the same time.
+///
+/// This is kept well below common file descriptor limits.
+pub const DEFAULT_JOBS: usize = 64;
+
 pub const MEM_PREALLOCATE_MAX: usize = 1024 * 1024 * 1024;
-pub const TERMINATOR_HACK: u8 = '|' as u8;
+pub const TERMINATOR_HACK: u8 = b'|';
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() {
 	use clap::*;
-	use std::path::PathBuf;
+	use std::{io::Write, path::PathBuf};
 
 	#[cfg(all(debug_assertions, feature = "nightly"))]
 	use std::error::Error;
 
+	let chunk_size_arg = Arg::with_name("chunk-size")
+		.help("Overrides the chunk_size in the addon's config (the maximum size of a networked chunk, in bytes)")
+		.long("chunk-size")
+		.takes_value(true)
+		.required(false)
+		.multiple(false);
+
+	let jobs_arg = Arg::with_name("jobs")
+		.help("The maximum number of files to read, copy or delete at the same time")
+		.long("jobs")
+		.short("j")
+		.takes_value(true)
+		.required(false)
+		.multiple(false);
+
 	let stdin = App::new("gluapack")
 		.version(env!("CARGO_PKG_VERSION"))
 		.setting(AppSettings::VersionlessSubcommands)
@@ -46,6 +75,29 @@ async fn main() {
 					.required(true)
 					.index(1)
 			)
+			.arg(chunk_size_arg.clone())
+			.arg(
+				Arg::with_name("no-cache")
+					.help("Packs everything from scratch, even if nothing has changed since the last pack")
+					.long("no-cache")
+					.multiple(false)
+			)
+			.arg(jobs_arg.clone())
+		)
+		.subcommand(
+			App::new("watch")
+			.setting(AppSettings::TrailingVarArg)
+			.setting(AppSettings::AllowLeadingHyphen)
+			.about("Packs an addon, then repacks it whenever its Lua files or config change")
+			.arg(
+				Arg::with_name("path")
+					.help("Path to addon root (directory containing lua/ folder)")
+					.takes_value(true)
+					.required(true)
+					.index(1)
+			)
+			.arg(chunk_size_arg)
+			.arg(jobs_arg.clone())
 		)
 		.subcommand(
 			App::new("unpack")
@@ -59,6 +111,97 @@ async fn main() {
 					.required(true)
 					.index(1)
 			)
+			.arg(
+				Arg::with_name("quarantine")
+					.help("Skips and logs packed files whose paths would escape the lua/ folder, instead of aborting")
+					.long("quarantine")
+					.multiple(false)
+			)
+			.arg(
+				Arg::with_name("recover-config")
+					.help("Writes a gluapack.json that packs the unpacked addon the same way it was packed")
+					.long("recover-config")
+					.multiple(false)
+			)
+			.arg(jobs_arg)
+		)
+		.subcommand(
+			App::new("unpack-cache")
+			.setting(AppSettings::TrailingVarArg)
+			.setting(AppSettings::AllowLeadingHyphen)
+			.about("Unpacks the clientside and shared files of packed addons from a client's Lua cache")
+			.arg(
+				Arg::with_name("path")
+					.help("Path to the Lua cache (garrysmod/cache/lua)")
+					.takes_value(true)
+					.required(true)
+					.index(1)
+			)
+			.arg(
+				Arg::with_name("manifest")
+					.help("Only unpacks the pack with this manifest.lua, rather than every pack found in the Lua cache")
+					.long("manifest")
+					.takes_value(true)
+					.multiple(false)
+			)
+			.arg(
+				Arg::with_name("quarantine")
+					.help("Skips and logs packed files whose paths would escape the lua/ folder, instead of aborting")
+					.long("quarantine")
+					.multiple(false)
+			)
+		)
+		.subcommand(
+			App::new("inspect")
+			.setting(AppSettings::TrailingVarArg)
+			.setting(AppSettings::AllowLeadingHyphen)
+			.about("Lists the files in a packed addon, without unpacking it")
+			.arg(
+				Arg::with_name("path")
+					.help("Path to addon root (directory containing lua/ folder)")
+					.takes_value(true)
+					.required(true)
+					.index(1)
+			)
+			.arg(
+				Arg::with_name("json")
+					.help("Prints the contents as JSON, rather than as a table")
+					.long("json")
+					.multiple(false)
+			)
+		)
+		.subcommand(
+			App::new("cat")
+			.setting(AppSettings::TrailingVarArg)
+			.setting(AppSettings::AllowLeadingHyphen)
+			.about("Prints packed files from a packed addon, without unpacking the rest of it")
+			.arg(
+				Arg::with_name("path")
+					.help("Path to addon root (directory containing lua/ folder)")
+					.takes_value(true)
+					.required(true)
+					.index(1)
+			)
+			.arg(
+				Arg::with_name("file")
+					.help("Path or glob pattern of the file(s) to print, relative to the lua/ folder (e.g. autorun/sh_init.lua)")
+					.takes_value(true)
+					.required(true)
+					.index(2)
+			)
+		)
+		.subcommand(
+			App::new("verify")
+			.setting(AppSettings::TrailingVarArg)
+			.setting(AppSettings::AllowLeadingHyphen)
+			.about("Checks a packed addon for missing or modified packed files, and exits with an error if it finds any")
+			.arg(
+				Arg::with_name("path")
+					.help("Path to addon root (directory containing lua/ folder)")
+					.takes_value(true)
+					.required(true)
+					.index(1)
+			)
 		)
 		.arg(
 			Arg::with_name("in-place")
@@ -80,6 +223,21 @@ async fn main() {
 				.multiple(false)
 				.conflicts_with("in-place")
 		)
+		.arg(
+			Arg::with_name("force")
+				.global(true)
+				.help("Deletes the output directory even if it doesn't look like one gluapack created")
+				.long("force")
+				.short("f")
+				.multiple(false)
+		)
+		.arg(
+			Arg::with_name("dry-run")
+				.global(true)
+				.help("Prints what would be copied, written and deleted, without changing the addon or output directory")
+				.long("dry-run")
+				.multiple(false)
+		)
 		.arg(
 			Arg::with_name("quiet")
 				.global(true)
@@ -120,10 +278,6 @@ async fn main() {
 					Some(out_dir) => {
 						let out_dir = PathBuf::from(out_dir);
 						if out_dir.is_absolute() {
-							if out_dir == $path {
-								eprintln!("ERROR: Output directory cannot be the same as the addon directory!");
-								abort!();
-							}
 							out_dir
 						} else {
 							$path.parent().unwrap_or_else(|| $path.as_path()).join(out_dir)
@@ -133,6 +287,19 @@ async fn main() {
 						let path = $path.file_name().unwrap().to_string_lossy();
 						$path.parent().unwrap_or_else(|| $path.as_path()).join(format!(concat!("{}-", $suffix_to), path.strip_suffix(concat!("-", $suffix_from)).unwrap_or_else(|| &path)))
 					}
+				}).map(|out_dir| {
+					if let Err(error) = util::check_output_dir(&$path, &out_dir) {
+						eprintln!("ERROR: {}", error);
+						abort!();
+					}
+					if !$args.is_present("force") {
+						if let Err(error) = util::check_output_dir_deletable(&out_dir) {
+							eprintln!("ERROR: {}", error);
+							eprintln!("Use --force if you're sure you want gluapack to delete it.");
+							abort!();
+						}
+					}
+					out_dir
 				})
 			} else {
 				None
@@ -140,6 +307,33 @@ async fn main() {
 		}
 	}
 
+	macro_rules! jobs {
+		($args:ident) => {
+			match $args.value_of("jobs").map(|jobs| jobs.parse::<usize>()) {
+				None => DEFAULT_JOBS,
+				Some(Ok(jobs)) if jobs > 0 => jobs,
+				Some(_) => {
+					eprintln!("ERROR: --jobs must be a number greater than 0");
+					abort!();
+				}
+			}
+		}
+	}
+
+	macro_rules! pack_options {
+		($args:ident) => {{
+			let chunk_size = $args.value_of("chunk-size").map(|chunk_size| match chunk_size.parse::<usize>() {
+				Ok(chunk_size) => chunk_size,
+				Err(_) => {
+					eprintln!("ERROR: --chunk-size must be a number of bytes");
+					abort!();
+				}
+			});
+
+			(chunk_size, jobs!($args))
+		}}
+	}
+
 	match stdin.subcommand() {
 		("pack", Some(args)) => {
 			let path = addon_path!(args);
@@ -147,14 +341,19 @@ async fn main() {
 			let out_dir = out_path!(args, path, in_place, "packed", "unpacked");
 			let no_copy = args.is_present("no-copy");
 			let quiet = args.is_present("quiet");
+			let no_cache = args.is_present("no-cache");
+			// The summary compares the chunk counts with and without compress_networked, which is only worth working out if it's printed
+			let compare_compression = !quiet;
+			let dry_run = args.is_present("dry-run");
+			let (chunk_size, jobs) = pack_options!(args);
 
-			match (quiet, Packer::pack(path, out_dir, no_copy, quiet).await) {
+			match (quiet, Packer::pack(path, out_dir, no_copy, chunk_size, jobs, no_cache, compare_compression, dry_run, quiet).await) {
 				(true, Ok(_)) => {},
 				(false, Ok((unpacked_files, packed_files, elapsed))) => {
 					println!();
 					let pct_change = (((unpacked_files as f64) - (packed_files as f64)) / (unpacked_files as f64)) * 100.;
 					let sign = if pct_change == 0. { "" } else if pct_change > 0. { "-" } else { "+" };
-					println!("Successfully PACKED {} file(s) -> {} files ({}{:.2}%)", unpacked_files, packed_files, sign, pct_change.abs());
+					println!("{} {} file(s) -> {} files ({}{:.2}%)", if dry_run { "Would have PACKED" } else { "Successfully PACKED" }, unpacked_files, packed_files, sign, pct_change.abs());
 					println!("Took {:?}", elapsed);
 				},
 				(_, Err(error)) => {
@@ -169,20 +368,79 @@ async fn main() {
 			}
 		},
 
+		("watch", Some(args)) => {
+			if args.is_present("dry-run") {
+				eprintln!("ERROR: --dry-run can't be used with watch");
+				abort!();
+			}
+
+			let path = addon_path!(args);
+			let in_place = args.is_present("in-place");
+			let out_dir = out_path!(args, path, in_place, "packed", "unpacked");
+			let no_copy = args.is_present("no-copy");
+			let quiet = args.is_present("quiet");
+			let (chunk_size, jobs) = pack_options!(args);
+
+			Watcher::watch(path, out_dir, no_copy, chunk_size, jobs, quiet).await
+		},
+
 		("unpack", Some(args)) => {
 			let path = addon_path!(args);
 			let in_place = args.is_present("in-place");
 			let out_dir = out_path!(args, path, in_place, "unpacked", "packed");
 			let no_copy = args.is_present("no-copy");
+			let quarantine = args.is_present("quarantine");
+			let recover_config = args.is_present("recover-config");
+			let jobs = jobs!(args);
+			let dry_run = args.is_present("dry-run");
 			let quiet = args.is_present("quiet");
 
-			match (quiet, Unpacker::unpack(path, out_dir, no_copy, quiet).await) {
+			match (quiet, Unpacker::unpack(path, out_dir, no_copy, quarantine, recover_config, jobs, dry_run, quiet).await) {
 				(true, Ok(_)) => {},
 				(false, Ok((packed_files, unpacked_files, elapsed))) => {
 					println!();
 					let pct_change = (((unpacked_files as f64) - (packed_files as f64)) / (unpacked_files as f64)) * 100.;
 					let sign = if pct_change == 0. { "" } else if pct_change > 0. { "-" } else { "+" };
-					println!("Successfully UNPACKED {} files -> {} file(s) ({}{:.2}%)", unpacked_files, packed_files, sign, pct_change.abs());
+					println!("{} {} files -> {} file(s) ({}{:.2}%)", if dry_run { "Would have UNPACKED" } else { "Successfully UNPACKED" }, unpacked_files, packed_files, sign, pct_change.abs());
+					println!("Took {:?}", elapsed);
+				},
+				(_, Err(error)) => {
+					if !quiet {
+						println!();
+					}
+					eprintln!("ERROR: {}", error);
+					#[cfg(all(feature = "nightly", debug_assertions))]
+					eprintln!("{:#?}", error.backtrace());
+					abort!();
+				},
+			}
+		},
+
+		("unpack-cache", Some(args)) => {
+			if args.is_present("in-place") || args.is_present("no-copy") {
+				eprintln!("ERROR: --in-place and --no-copy can't be used with unpack-cache");
+				abort!();
+			}
+
+			let path = PathBuf::from(args.value_of("path").unwrap());
+			if !path.is_dir() {
+				eprintln!("ERROR: Couldn't find a Lua cache at this path.");
+				abort!();
+			}
+			let path = dunce::canonicalize(&path).unwrap_or(path);
+
+			let in_place = false;
+			let out_dir = out_path!(args, path, in_place, "unpacked", "packed").unwrap();
+			let manifest = args.value_of("manifest").map(PathBuf::from);
+			let quarantine = args.is_present("quarantine");
+			let dry_run = args.is_present("dry-run");
+			let quiet = args.is_present("quiet");
+
+			match (quiet, Unpacker::unpack_cache(path, manifest, out_dir, quarantine, dry_run, quiet).await) {
+				(true, Ok(_)) => {},
+				(false, Ok((unpacked_files, cache_files, elapsed))) => {
+					println!();
+					println!("{} {} cached files -> {} file(s)", if dry_run { "Would have UNPACKED" } else { "Successfully UNPACKED" }, cache_files, unpacked_files);
 					println!("Took {:?}", elapsed);
 				},
 				(_, Err(error)) => {
@@ -197,6 +455,119 @@ async fn main() {
 			}
 		},
 
+		("cat", Some(args)) => {
+			let path = addon_path!(args);
+			let quiet = args.is_present("quiet");
+
+			let pattern = match glob::Pattern::new(args.value_of("file").unwrap()) {
+				Ok(pattern) => pattern.into(),
+				Err(error) => {
+					eprintln!("ERROR: Invalid file pattern: {}", error);
+					abort!();
+				}
+			};
+
+			// Files are written to the output directory only if one is given, and nothing in it is deleted
+			let out_dir = args.value_of("out").map(|out_dir| {
+				let out_dir = PathBuf::from(out_dir);
+				if out_dir.is_absolute() {
+					out_dir
+				} else {
+					path.parent().unwrap_or(&path).join(out_dir)
+				}
+			});
+
+			match Unpacker::cat(path, pattern, out_dir, quiet).await {
+				Ok(0) => {
+					eprintln!("ERROR: No packed files match {:?}", args.value_of("file").unwrap());
+					abort!();
+				},
+				Ok(_) => {},
+				// e.g. piped into head
+				Err(unpack::UnpackingError::IoError { error, .. }) if error.kind() == std::io::ErrorKind::BrokenPipe => {},
+				Err(error) => {
+					eprintln!("ERROR: {}", error);
+					#[cfg(all(feature = "nightly", debug_assertions))]
+					eprintln!("{:#?}", error.backtrace());
+					abort!();
+				}
+			}
+		},
+
+		("inspect", Some(args)) => {
+			let path = addon_path!(args);
+
+			let inspected = Unpacker::inspect(path).await;
+
+			let stdout = std::io::stdout();
+			let mut stdout = stdout.lock();
+			let written = match inspected {
+				Ok(packs) if args.is_present("json") => writeln!(stdout, "{}", serde_json::to_string_pretty(&packs).unwrap()),
+				Ok(packs) if packs.is_empty() => writeln!(stdout, "This addon doesn't contain any packed addons."),
+				Ok(packs) => {
+					packs.iter().enumerate().try_for_each(|(i, pack)| {
+						if i != 0 {
+							writeln!(stdout)?;
+						}
+						pack.print_table(&mut stdout)
+					})
+				},
+				Err(error) => {
+					eprintln!("ERROR: {}", error);
+					#[cfg(all(feature = "nightly", debug_assertions))]
+					eprintln!("{:#?}", error.backtrace());
+					abort!();
+				}
+			};
+
+			match written {
+				// e.g. piped into head
+				Err(error) if error.kind() == std::io::ErrorKind::BrokenPipe => {},
+				Err(error) => {
+					eprintln!("ERROR: {}", error);
+					abort!();
+				},
+				Ok(()) => {}
+			}
+		},
+
+		("verify", Some(args)) => {
+			let path = addon_path!(args);
+			let quiet = args.is_present("quiet");
+
+			match Unpacker::verify(path).await {
+				Ok(packs) if packs.is_empty() => {
+					eprintln!("ERROR: This addon doesn't contain any packed addons.");
+					abort!();
+				},
+				Ok(packs) => {
+					let mut problems = 0;
+					for pack in packs {
+						if pack.problems.is_empty() {
+							quietln!(quiet, "{}: OK", pack.unique_id);
+						} else {
+							quietln!(quiet, "{}: {} problem(s)", pack.unique_id, pack.problems.len());
+							for problem in pack.problems.iter() {
+								eprintln!("ERROR: {}", problem);
+							}
+							problems += pack.problems.len();
+						}
+					}
+					if problems != 0 {
+						quietln!(quiet);
+						quietln!(quiet, "Verification FAILED with {} problem(s)", problems);
+						abort!();
+					}
+				},
+				Err(error) => {
+					eprintln!("ERROR: {}", error);
+					#[cfg(all(feature = "nightly", debug_assertions))]
+					eprintln!("{:#?}", error.backtrace());
+					abort!();
+				}
+			}
+		},
+
 		_ => unreachable!()
 	}
 }
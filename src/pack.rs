@@ -1,37 +1,422 @@
 // The order of operations should be: sv cl sh
 
-use crate::{MAX_LUA_SIZE, MEM_PREALLOCATE_MAX, TERMINATOR_HACK, util, config::{Config, GlobPattern}};
-use std::{collections::HashSet, convert::TryInto, path::PathBuf, time::Duration};
-use futures_util::{FutureExt, future};
+use crate::{cache::PackCache, comment, escape, header::{Compression, PackHeader, PACK_FORMAT_VERSION}, MAX_LUA_SIZE, MIN_LUA_SIZE, TERMINATOR_HACK, util, config::{Config, GlobPattern}};
+use std::{collections::{BTreeMap, BTreeSet, HashSet}, convert::TryInto, ffi::{OsStr, OsString}, fs::File, io::{BufWriter, Cursor, Read, Seek, Write}, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::Duration};
+use futures_util::{StreamExt, TryStreamExt, stream};
 use sha2::Digest;
 
-/// Lua comment
-const COMMENT_START: &'static [u8; 2] = b"--";
+/// Moves a split point in escaped bytes back by one if it would split an escape sequence in two.
+///
+/// Chunks are unescaped individually, so escape sequences can't span two chunks.
+fn escape_split_point(escaped: &[u8], at: usize) -> usize {
+	if at > 0 && escaped[at - 1] == escape::ESCAPE_BYTE {
+		at - 1
+	} else {
+		at
+	}
+}
+
+/// Whether a new networked chunk should be started at this file, if the chunk being filled is at least [`CHUNK_ANCHOR_MIN_FILL`] full.
+///
+/// Roughly one in every `CHUNK_ANCHOR_INTERVAL` files is an anchor.
+fn is_chunk_anchor(path: &str) -> bool {
+	const CHUNK_ANCHOR_INTERVAL: u8 = 8;
+	sha2::Sha256::digest(path.as_bytes())[0] % CHUNK_ANCHOR_INTERVAL == 0
+}
+
+/// How full a chunk must be, as a fraction of its maximum length, before an anchor can cut it.
+///
+/// Without this, anchors would cut chunks no matter how small they are, and packs would have several times as many chunks as they need.
+const CHUNK_ANCHOR_MIN_FILL: (usize, usize) = (3, 4);
+
+pub fn hash_hex(hash: &[u8]) -> String {
+	let mut hex = String::with_capacity(hash.len() * 2);
+	for byte in hash.iter() {
+		hex.push_str(&format!("{:02x}", byte));
+	}
+	hex
+}
+
+/// The name a networked chunk is given in the client's Lua cache (`garrysmod/cache/lua/<hash>.lua`), which is listed in `manifest.lua`.
+pub fn lua_cache_hash(commented_chunk: &[u8]) -> [u8; 20] {
+	let mut sha256 = sha2::Sha256::new();
+	sha256.update(commented_chunk);
+	sha256.update([0u8]);
+
+	let sha256 = sha256.finalize();
+	sha256[0..20].try_into().unwrap()
+}
+
+/// Chunks are sized for a long comment of level [`comment::RESERVED_LEVEL`].
+/// If a chunk's contents need a deeper level than that, it's split in half until it fits.
+fn fit_chunk<F: FnMut(Vec<u8>) -> Result<(), PackingError>>(chunk: Vec<u8>, chunk_size: usize, emit: &mut F) -> Result<(), PackingError> {
+	if comment::commented_len(&chunk) <= chunk_size {
+		emit(chunk)
+	} else {
+		let (head, tail) = chunk.split_at(escape_split_point(&chunk, chunk.len() / 2));
+		fit_chunk(head.to_vec(), chunk_size, emit)?;
+		fit_chunk(tail.to_vec(), chunk_size, emit)
+	}
+}
+
+/// Cuts a realm's escaped pack into chunks as it's written, passing each chunk to `emit` as soon as it's full.
+///
+/// Only the chunk that's being filled is kept in memory.
+struct Chunker<F: FnMut(Vec<u8>) -> Result<(), PackingError>> {
+	chunk: Vec<u8>,
+	header_len: usize,
+	chunk_size: usize,
+	emit: F
+}
+impl<F: FnMut(Vec<u8>) -> Result<(), PackingError>> Chunker<F> {
+	/// The first chunk starts with the realm's [`PackHeader`].
+	fn new(header: Vec<u8>, chunk_size: usize, emit: F) -> Self {
+		let mut chunk = Vec::with_capacity(chunk_size);
+		chunk.extend_from_slice(&header);
+		Chunker {
+			chunk,
+			header_len: header.len(),
+			chunk_size,
+			emit
+		}
+	}
+
+	/// The maximum length of a chunk before it's commented.
+	fn max_len(&self) -> usize {
+		self.chunk_size - comment::RESERVED_OVERHEAD
+	}
+
+	fn chunk_len(&self) -> usize {
+		self.chunk.len()
+	}
+
+	/// Whether the chunk being filled is full enough to be cut at an anchor.
+	fn is_anchorable(&self) -> bool {
+		let (numerator, denominator) = CHUNK_ANCHOR_MIN_FILL;
+		self.chunk.len() * denominator >= self.max_len() * numerator
+	}
+
+	/// Whether the chunk being filled holds anything other than the pack header.
+	fn has_entries(&self) -> bool {
+		self.chunk.len() > self.header_len
+	}
+
+	/// Appends escaped bytes to the chunk being filled, cutting it whenever it's full.
+	///
+	/// Chunks are unescaped individually, so escape sequences are never split between two chunks.
+	fn push(&mut self, mut escaped: &[u8]) -> Result<(), PackingError> {
+		let max_len = self.max_len();
+		while self.chunk.len() + escaped.len() > max_len {
+			let (head, tail) = escaped.split_at(escape_split_point(escaped, max_len - self.chunk.len()));
+			self.chunk.extend_from_slice(head);
+			self.cut()?;
+			escaped = tail;
+		}
+		self.chunk.extend_from_slice(escaped);
+		Ok(())
+	}
+
+	/// Passes the chunk being filled to `emit`, and starts a new one.
+	fn cut(&mut self) -> Result<(), PackingError> {
+		let chunk = std::mem::replace(&mut self.chunk, Vec::with_capacity(self.chunk_size));
+		self.header_len = 0;
+		fit_chunk(chunk, self.chunk_size, &mut self.emit)
+	}
+
+	fn finish(mut self) -> Result<(), PackingError> {
+		self.cut()
+	}
+}
+
+/// Hashes everything that's written through it.
+struct HashingWriter<W: Write> {
+	inner: W,
+	sha256: sha2::Sha256
+}
+impl<W: Write> Write for HashingWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		let written = self.inner.write(buf)?;
+		self.sha256.update(&buf[..written]);
+		Ok(written)
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+/// A file being written to a [`Staging`] area.
+enum StagedFile {
+	File(BufWriter<File>),
+	Planned {
+		file_name: OsString,
+		len: u64,
+		sha256: sha2::Sha256
+	}
+}
+impl Write for StagedFile {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		match self {
+			StagedFile::File(file) => file.write(buf),
+			StagedFile::Planned { len, sha256, .. } => {
+				*len += buf.len() as u64;
+				sha256.update(buf);
+				Ok(buf.len())
+			}
+		}
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		match self {
+			StagedFile::File(file) => file.flush(),
+			StagedFile::Planned { .. } => Ok(())
+		}
+	}
+}
+
+/// Where packed files are written until the unique ID is known.
+///
+/// This is normally a directory, which is deleted if packing fails. A dry run doesn't write anything: it just keeps the length and hash of each file,
+/// which is all it needs to report exactly which packed files would change.
+enum Staging {
+	Dir(PathBuf),
+	Planned(Mutex<BTreeMap<OsString, (u64, Vec<u8>)>>)
+}
+impl Staging {
+	fn create_dir(path: PathBuf) -> Result<Staging, std::io::Error> {
+		// Left behind if gluapack was killed mid-pack
+		if path.is_dir() {
+			std::fs::remove_dir_all(&path)?;
+		}
+		std::fs::create_dir_all(&path)?;
+		Ok(Staging::Dir(path))
+	}
+
+	fn planned() -> Staging {
+		Staging::Planned(Mutex::new(BTreeMap::new()))
+	}
+
+	/// Starts writing a staged file. It must be passed to [`Staging::finish`] once it's written.
+	fn create(&self, file_name: &str) -> Result<StagedFile, std::io::Error> {
+		match self {
+			Staging::Dir(dir) => Ok(StagedFile::File(BufWriter::new(File::create(dir.join(file_name))?))),
+			Staging::Planned(_) => Ok(StagedFile::Planned {
+				file_name: file_name.into(),
+				len: 0,
+				sha256: sha2::Sha256::new()
+			})
+		}
+	}
+
+	fn finish(&self, file: StagedFile) -> Result<(), std::io::Error> {
+		match (self, file) {
+			(Staging::Planned(planned), StagedFile::Planned { file_name, len, sha256 }) => {
+				planned.lock().unwrap().insert(file_name, (len, sha256.finalize().to_vec()));
+				Ok(())
+			},
+			(_, mut file) => file.flush()
+		}
+	}
+
+	fn write(&self, file_name: &str, contents: &[u8]) -> Result<(), std::io::Error> {
+		let mut file = self.create(file_name)?;
+		file.write_all(contents)?;
+		self.finish(file)
+	}
+
+	fn len(&self, file_name: &OsStr) -> Result<u64, std::io::Error> {
+		match self {
+			Staging::Dir(dir) => Ok(std::fs::metadata(dir.join(file_name))?.len()),
+			Staging::Planned(planned) => Ok(planned.lock().unwrap().get(file_name).map(|(len, _)| *len).unwrap_or(0))
+		}
+	}
+
+	/// Deletes a temporary file that was written to the staging directory outside of [`Staging::create`].
+	fn remove(&self, file_name: &str) -> Result<(), std::io::Error> {
+		match self {
+			Staging::Dir(dir) => std::fs::remove_file(dir.join(file_name)),
+			Staging::Planned(_) => Ok(())
+		}
+	}
+
+	/// Compares the staged files with a directory, returning the names of the staged files that differ from it, and the paths of the files in it that weren't staged.
+	fn diff(&self, to: &Path) -> Result<(Vec<OsString>, Vec<PathBuf>), std::io::Error> {
+		let mut changed = vec![];
+		let mut staged = HashSet::new();
+		match self {
+			Staging::Dir(dir) => {
+				for entry in std::fs::read_dir(dir)? {
+					let entry = entry?;
+					if !util::files_equal(&entry.path(), &to.join(entry.file_name()))? {
+						changed.push(entry.file_name());
+					}
+					staged.insert(entry.file_name());
+				}
+			},
+			Staging::Planned(planned) => {
+				for (file_name, (len, sha256)) in planned.lock().unwrap().iter() {
+					let path = to.join(file_name);
+					let unchanged = match std::fs::metadata(&path) {
+						Ok(metadata) if metadata.len() == *len => {
+							let mut existing = sha2::Sha256::new();
+							std::io::copy(&mut File::open(&path)?, &mut existing)?;
+							existing.finalize()[..] == sha256[..]
+						},
+						Ok(_) => false,
+						Err(error) if error.kind() == std::io::ErrorKind::NotFound => false,
+						Err(error) => return Err(error)
+					};
+					if !unchanged {
+						changed.push(file_name.clone());
+					}
+					staged.insert(file_name.clone());
+				}
+			}
+		}
+		changed.sort_unstable();
+
+		let mut removed = vec![];
+		if to.is_dir() {
+			for entry in std::fs::read_dir(to)? {
+				let entry = entry?;
+				if !staged.contains(&entry.file_name()) {
+					removed.push(entry.path());
+				}
+			}
+		}
+		removed.sort_unstable();
 
-/// Prepends `--` to every line in the byte vector.
-fn commentify(bytes: Vec<u8>) -> Vec<u8> {
-	const NEWLINE: u8 = '\n' as u8;
-	let mut escaped = Vec::with_capacity(bytes.len());
-	escaped.push('-' as u8);
-	escaped.push('-' as u8);
-	for byte in bytes {
-		escaped.push(byte);
-		if byte == NEWLINE {
-			escaped.reserve(2);
-			escaped.push('-' as u8);
-			escaped.push('-' as u8);
+		Ok((changed, removed))
+	}
+
+	fn dir(&self) -> &Path {
+		match self {
+			Staging::Dir(dir) => dir,
+			Staging::Planned(_) => unreachable!("A dry run never writes its staged files")
+		}
+	}
+
+	/// Moves the staged files to their final directory.
+	fn persist(&self, to: &Path) -> Result<(), std::io::Error> {
+		std::fs::rename(self.dir(), to)
+	}
+
+	/// Moves the staged files into a directory left by a previous pack, leaving the files that haven't changed untouched.
+	fn sync(&self, to: &Path) -> Result<(), std::io::Error> {
+		let (changed, removed) = self.diff(to)?;
+		for file_name in changed {
+			std::fs::rename(self.dir().join(&file_name), to.join(file_name))?;
+		}
+		for path in removed {
+			if path.is_dir() {
+				std::fs::remove_dir_all(path)?;
+			} else {
+				std::fs::remove_file(path)?;
+			}
 		}
+		Ok(())
 	}
-	escaped
+}
+impl Drop for Staging {
+	fn drop(&mut self) {
+		if let Staging::Dir(dir) = self {
+			std::fs::remove_dir_all(dir).ok();
+		}
+	}
+}
+
+/// The directories and files to copy from an addon to the output directory, as (from, to) pairs.
+struct AddonFiles {
+	dirs: Vec<PathBuf>,
+	files: Vec<(PathBuf, PathBuf)>
 }
 
+/// Formats `path` relative to `root`, with forward slashes.
+fn relative_path(root: &Path, path: &Path) -> String {
+	path.strip_prefix(root).unwrap_or(path).to_string_lossy().replace('\\', "/")
+}
+
+/// A Lua file that's going to be packed.
+///
+/// Its contents aren't kept in memory. They're streamed from disk while packing, so that huge addons can be packed with little memory.
 struct LuaFile {
 	path: String,
-	contents: Vec<u8>
+	fs_path: PathBuf,
+	len: u64
 }
-impl std::hash::Hash for LuaFile {
-	fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-		self.path.hash(state)
+impl LuaFile {
+	const BLOCK_SIZE: usize = 64 * 1024;
+
+	/// Reads the file's contents in blocks, making sure it hasn't changed size since it was collected.
+	fn read_blocks(&self, mut f: impl FnMut(&[u8]) -> Result<(), PackingError>) -> Result<(), PackingError> {
+		let changed = || error!(PackingError::UnpackableFile {
+			path: self.path.clone(),
+			reason: "it changed while it was being packed"
+		});
+
+		let mut file = File::open(&self.fs_path)?;
+		let mut block = vec![0; LuaFile::BLOCK_SIZE];
+		let mut remaining = self.len;
+		loop {
+			let read = file.read(&mut block)?;
+			if read == 0 {
+				break;
+			} else if read as u64 > remaining {
+				return Err(changed());
+			}
+			remaining -= read as u64;
+			f(&block[..read])?;
+		}
+		if remaining != 0 {
+			return Err(changed());
+		}
+
+		Ok(())
+	}
+
+	/// Reads the whole file into memory.
+	fn read(&self) -> Result<Vec<u8>, PackingError> {
+		let mut contents = Vec::with_capacity(self.len as usize);
+		self.read_blocks(|block| {
+			contents.extend_from_slice(block);
+			Ok(())
+		})?;
+		Ok(contents)
+	}
+
+	/// The path and length that precede the file's contents in a pack.
+	fn entry_prefix(&self, is_sent_to_client: bool) -> Vec<u8> {
+		let mut prefix = Vec::with_capacity(self.path.len() + 18);
+		if is_sent_to_client {
+			// We can't use NUL to terminate because clientside Lua files will only send up to the NUL byte (fucking C strings)
+			// Instead, the lengths of the path and the file are written as hex strings terminated by a |, followed by the path itself
+			// Any NUL bytes left in the path or file are escaped when the chunk is escaped
+			prefix.extend_from_slice(format!("{:x}", self.path.len()).as_bytes());
+			prefix.push(TERMINATOR_HACK);
+			prefix.extend_from_slice(format!("{:x}", self.len).as_bytes());
+			prefix.push(TERMINATOR_HACK);
+			prefix.extend_from_slice(self.path.as_bytes());
+		} else {
+			prefix.extend_from_slice(self.path.as_bytes());
+			prefix.push(0);
+			prefix.extend_from_slice(&(self.len as u32).to_le_bytes());
+		}
+		prefix
+	}
+
+	/// The number of bytes [`LuaFile::write_entry`] will write for this Lua file.
+	fn packed_len(&self, is_sent_to_client: bool) -> u64 {
+		self.path.len() as u64 + self.len + if is_sent_to_client {
+			(format!("{:x}", self.path.len()).len() + format!("{:x}", self.len).len() + 2) as u64
+		} else {
+			5
+		}
+	}
+
+	/// Writes the file's path, length and contents into a pack.
+	fn write_entry<W: Write>(&self, w: &mut W, is_sent_to_client: bool) -> Result<(), PackingError> {
+		w.write_all(&self.entry_prefix(is_sent_to_client))?;
+		self.read_blocks(|block| Ok(w.write_all(block)?))
 	}
 }
 impl PartialEq for LuaFile {
@@ -40,33 +425,68 @@ impl PartialEq for LuaFile {
 	}
 }
 impl Eq for LuaFile {}
+impl PartialOrd for LuaFile {
+	fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+		Some(self.cmp(other))
+	}
+}
+impl Ord for LuaFile {
+	fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+		self.path.cmp(&other.path)
+	}
+}
 
 pub struct Packer {
 	pub dir: PathBuf,
 	pub out_dir: PathBuf,
 	pub config: Config,
 	pub unique_id: Option<String>,
+	pub jobs: usize,
+	pub dry_run: bool,
 	pub quiet: bool
 }
 impl Packer {
-	pub async fn pack(dir: PathBuf, out_dir: Option<PathBuf>, no_copy: bool, quiet: bool) -> Result<(usize, usize, Duration), PackingError> {
+	#[allow(clippy::too_many_arguments)]
+	pub async fn pack(dir: PathBuf, out_dir: Option<PathBuf>, no_copy: bool, chunk_size: Option<usize>, jobs: usize, no_cache: bool, compare_compression: bool, dry_run: bool, quiet: bool) -> Result<(usize, usize, Duration), PackingError> {
 		let mut config = {
-			let config_path = dir.join("gluapack.json");
-			if config_path.is_file() {
+			if let Some(config_path) = Config::discover(&dir)? {
 				Config::read(config_path)?
 			} else {
-				quietln!(quiet, "WARNING: Couldn't find gluapack.json in your addon. Using the default config.");
+				quietln!(quiet, "WARNING: Couldn't find gluapack.json, gluapack.toml or gluapack.yaml in your addon. Using the default config.");
 				Config::default()
 			}
 		};
 
+		if let Some(chunk_size) = chunk_size {
+			Config::validate_chunk_size(chunk_size)?;
+			config.chunk_size = chunk_size;
+		}
+
 		if !quiet {
 			config.dump_json();
 			println!("Addon Path: {}", util::canonicalize(&dir).display());
 		}
 
+		let previous_chunk_hashes = Packer::previous_chunk_hashes(out_dir.as_ref().unwrap_or(&dir));
+
+		// If the last pack was made with the same config and options, its output is kept and only what has changed is rewritten
+		let fingerprint = PackCache::fingerprint(&config, out_dir.is_none(), no_copy);
+		let previous_cache = if no_cache {
+			None
+		} else {
+			PackCache::read(out_dir.as_ref().unwrap_or(&dir)).filter(|cache| cache.fingerprint == fingerprint)
+		};
+
+		let surviving_unique_ids = Packer::surviving_unique_ids(&dir, out_dir.as_ref().unwrap_or(&dir), out_dir.is_none(), no_copy, previous_cache.as_ref());
+
+		// A unique ID from the config can be checked straight away. One that's a hash of the packed files is checked once they've been staged.
+		// Either way, nothing is deleted until it has been checked.
+		if let Some(unique_id) = &config.unique_id {
+			Packer::check_unique_id_collision(unique_id, &surviving_unique_ids)?;
+		}
+
 		let (in_place, out_dir) = if let Some(out_dir) = out_dir {
-			util::prepare_output_dir(quiet, &out_dir).await;
+			quietln!(quiet, "Output Path: {}", util::canonicalize(&out_dir).display());
 			(false, out_dir)
 		} else {
 			quietln!(quiet, "Output Path: In-place");
@@ -89,6 +509,8 @@ impl Packer {
 			dir,
 			config,
 			unique_id: None,
+			jobs,
+			dry_run,
 			quiet
 		};
 
@@ -115,28 +537,102 @@ impl Packer {
 			}
 		}
 
+		packer.check_lua_files(sv.iter().chain(cl.iter()).chain(sh.iter()))?;
+
 		let total_unpacked_files = sv.len() + cl.len() + sh.len();
 		if total_unpacked_files == 0 {
 			return Err(error!(PackingError::NoLuaFiles));
 		}
 
-		if !in_place {
-			if !no_copy {
-				quietln!(quiet, "Copying addon to output directory...");
-				packer.copy_addon().await?;
-			}
+		let out_root = packer.out_dir.parent().unwrap().to_path_buf(); // pop lua/
+		let addon_files = if !in_place && !no_copy {
+			Some(packer.walk_addon().await?)
 		} else {
-			quietln!(quiet, "Deleting old gluapack files...");
-			packer.delete_old_gluapack_files().await?;
-		}
+			None
+		};
+
+		quietln!(quiet, "Checking for changes...");
+
+		let packed_paths = sv.iter().chain(cl.iter()).chain(sh.iter()).map(|lua_file| format!("lua/{}", lua_file.path)).collect::<HashSet<_>>();
+		let sources = {
+			let mut sources = sv.iter().chain(cl.iter()).chain(sh.iter()).map(|lua_file| (format!("lua/{}", lua_file.path), lua_file.fs_path.clone())).collect::<Vec<_>>();
+			if let Some(addon_files) = &addon_files {
+				sources.extend(addon_files.files.iter().map(|(from, to)| (relative_path(&out_root, to), from.clone())));
+			}
+			PackCache::hash_sources(sources, previous_cache.as_ref(), jobs).await?
+		};
+
+		let previous_cache = match previous_cache {
+			Some(previous_cache) if previous_cache.is_unchanged(&sources) && previous_cache.outputs_exist(&out_root) => {
+				Packer::check_unique_id_collision(&previous_cache.unique_id, &surviving_unique_ids)?;
+
+				quietln!(quiet, "Nothing has changed since the last pack. Use --no-cache to pack anyway.");
+
+				// Remember any new modification times, so that touched files aren't hashed again next time
+				let (unpacked_files, packed_files) = (previous_cache.unpacked_files, previous_cache.packed_files);
+				if !dry_run {
+					PackCache { sources, ..previous_cache }.write(&out_root).await?;
+				}
+
+				return Ok((unpacked_files, packed_files, started.elapsed()));
+			},
+			previous_cache => previous_cache
+		};
+
+		// Without a cache, the output directory is recreated from scratch
+		let recreate_out_root = !in_place && previous_cache.is_none();
 
 		quietln!(quiet, "Packing...");
 
-		let ((sv_paths, sv), (cl_paths, cl), (sh_paths, sh)) = tokio::try_join!(
-			tokio::task::spawn_blocking(move || Packer::pack_lua_files(sv, false)),
-			tokio::task::spawn_blocking(move || Packer::pack_lua_files(cl, true)),
-			tokio::task::spawn_blocking(move || Packer::pack_lua_files(sh, true))
-		).expect("Failed to join threads");
+		// Everything is written to a staging directory first, because the unique ID may be a hash of the packed files,
+		// and nothing in the output is deleted until the staged pack has passed its checks.
+		// If the output directory is going to be recreated, the staging directory is kept next to it instead of in it.
+		let staging = Arc::new(if dry_run {
+			Staging::planned()
+		} else if recreate_out_root {
+			let out_root = util::canonicalize_lenient(&out_root);
+			Staging::create_dir(out_root.with_file_name(format!(".{}.gluapack-staging", out_root.file_name().unwrap().to_string_lossy())))?
+		} else {
+			Staging::create_dir(packer.out_dir.join(".gluapack-staging"))?
+		});
+
+		let (compression, other_compression) = if packer.config.compress_networked {
+			(Compression::Lzma, Compression::None)
+		} else {
+			(Compression::None, Compression::Lzma)
+		};
+
+		let sv_compression = if packer.config.compress_sv { Compression::Lzma } else { Compression::None };
+
+		// Unless the summary won't be printed, the networked files are also chunked the other way, so we can tell the user what difference compress_networked makes
+		let chunk_size = packer.config.chunk_size;
+		let write_networked_chunks = |lua_files: BTreeSet<LuaFile>, realm| {
+			let staging = staging.clone();
+			tokio::task::spawn_blocking(move || -> Result<_, PackingError> {
+				let (hashes, digest) = Packer::write_networked_chunks(&lua_files, realm, compression, chunk_size, &staging)?;
+				let other_chunks = if compare_compression {
+					Some(Packer::count_networked_chunks(&lua_files, realm, other_compression, chunk_size, &staging)?)
+				} else {
+					None
+				};
+				Ok((lua_files.into_iter().map(|lua_file| lua_file.path).collect::<Vec<_>>(), hashes, digest, other_chunks))
+			})
+		};
+
+		let sv_staging = staging.clone();
+		let (sv, cl, sh) = tokio::try_join!(
+			tokio::task::spawn_blocking(move || -> Result<_, PackingError> {
+				let digest = Packer::write_sv_pack(&sv, sv_compression, &sv_staging)?;
+				Ok((sv.into_iter().map(|lua_file| lua_file.path).collect::<Vec<_>>(), digest))
+			}),
+			write_networked_chunks(cl, "cl"),
+			write_networked_chunks(sh, "sh")
+		).map_err(std::io::Error::from)?;
+
+		let ((sv_paths, sv_digest), (cl_paths, hashes_cl, _, other_cl_chunks), (sh_paths, hashes_sh, sh_digest, other_sh_chunks)) = (sv?, cl?, sh?);
+
+		packer.check_chunk_limit("cl", hashes_cl.len(), packer.config.max_chunks_cl)?;
+		packer.check_chunk_limit("sh", hashes_sh.len(), packer.config.max_chunks_sh)?;
 
 		packer.unique_id = Some(packer.config.unique_id.as_ref().map(|x| x.to_owned()).unwrap_or_else(|| {
 			const HASH_SUBHEX_LENGTH: usize = 16;
@@ -144,44 +640,133 @@ impl Packer {
 			quietln!(quiet, "Calculating hash...");
 
 			let mut sha256 = sha2::Sha256::new();
-			sha256.update(&sv);
-			sha256.update(&sh);
+			sha256.update(&sv_digest);
+			sha256.update(&sh_digest);
 			format!("{:x}", sha256.finalize())[0..HASH_SUBHEX_LENGTH].to_string()
 		}));
 
-		tokio::fs::create_dir_all(packer.out_dir.join(&format!("gluapack/{}", packer.unique_id()))).await.expect("Failed to create gluapack directory");
+		Packer::check_unique_id_collision(packer.unique_id(), &surviving_unique_ids)?;
+
+		let total_packed_files = hashes_cl.len() + hashes_sh.len();
+		if total_packed_files > 0 {
+			quietln!(quiet, "Generating clientside Lua cache manifest...");
+			packer.generate_cache_manifest(&staging, &hashes_cl, &hashes_sh)?;
+
+			match (compression, other_cl_chunks.zip(other_sh_chunks)) {
+				(Compression::Lzma, Some((other_cl_chunks, other_sh_chunks))) => quietln!(quiet, "{} compressed networked chunk(s) ({} without compress_networked)", total_packed_files, other_cl_chunks + other_sh_chunks),
+				(Compression::Lzma, None) => quietln!(quiet, "{} compressed networked chunk(s)", total_packed_files),
+				(Compression::None, Some((other_cl_chunks, other_sh_chunks))) => quietln!(quiet, "{} networked chunk(s) ({} with compress_networked)", total_packed_files, other_cl_chunks + other_sh_chunks),
+				(Compression::None, None) => quietln!(quiet, "{} networked chunk(s)", total_packed_files)
+			}
+
+			if !previous_chunk_hashes.is_empty() {
+				let changed_chunks = hashes_cl.iter().chain(hashes_sh.iter()).filter(|hash| !previous_chunk_hashes.contains(&hash_hex(*hash))).count();
+				quietln!(quiet, "{} of {} networked chunk(s) changed since the previous pack", changed_chunks, total_packed_files);
+			}
+		}
+
+		// The pack has passed every check, so the old output can be replaced
+		if recreate_out_root {
+			if dry_run {
+				if out_root.exists() {
+					quietln!(quiet, "Would delete the old output directory");
+				}
+			} else {
+				util::recreate_output_dir(quiet, &out_root).await?;
+			}
+		}
 
-		if !sv.is_empty() {
-			quietln!(quiet, "Writing packed serverside files...");
-			tokio::fs::write(packer.out_dir.join(&format!("gluapack/{}/gluapack.sv.lua", packer.unique_id())), sv).await?;
+		if !dry_run {
+			PackCache::invalidate(&out_root).await?;
 		}
 
-		let total_packed_files = if !cl.is_empty() || !sh.is_empty() {
-			quietln!(quiet, "Chunking...");
+		let mut copied = BTreeSet::new();
+		if !in_place {
+			if let Some(mut addon_files) = addon_files {
+				// Packed files are never copied
+				copied = addon_files.files.iter().map(|(_, to)| relative_path(&out_root, to)).filter(|path| !packed_paths.contains(path)).collect();
+				addon_files.files.retain(|(_, to)| copied.contains(&relative_path(&out_root, to)));
+
+				if let Some(previous_cache) = &previous_cache {
+					// Other files are only copied if they've changed
+					addon_files.files.retain(|(_, to)| {
+						let path = relative_path(&out_root, to);
+						!(previous_cache.copied.contains(&path) && previous_cache.is_source_unchanged(&path, &sources) && to.is_file())
+					});
+
+					let stale = previous_cache.copied.difference(&copied).cloned().collect::<Vec<_>>();
+					if !stale.is_empty() {
+						quietln!(quiet, "Deleting files removed from the addon...");
+						packer.delete_files(&out_root, stale).await?;
+					}
 
-			let ((hashes_cl, chunk_n_cl), (hashes_sh, chunk_n_sh)) = tokio::try_join!(
-				packer.write_packed_chunks(cl, "cl"),
-				packer.write_packed_chunks(sh, "sh"),
-			)?;
+					quietln!(quiet, "Copying {} changed file(s) to output directory...", addon_files.files.len());
+				} else {
+					quietln!(quiet, "Copying addon to output directory...");
+				}
+
+				packer.copy_addon(addon_files).await?;
+			}
+		} else {
+			quietln!(quiet, "Deleting old gluapack files...");
+			packer.delete_old_gluapack_files(previous_cache.as_ref().map(|cache| cache.unique_id.as_str())).await?;
+		}
 
-			if !hashes_cl.is_empty() || !hashes_sh.is_empty() {
-				quietln!(quiet, "Generating clientside Lua cache manifest...");
-				packer.generate_cache_manifest(hashes_cl, hashes_sh).await?;
+		if let Some(previous_cache) = &previous_cache {
+			if &previous_cache.unique_id != packer.unique_id() {
+				// The unique ID has changed, so everything the last pack wrote is replaced
+				packer.delete_files(&out_root, previous_cache.packed.iter().cloned().collect()).await?;
 			}
+		}
 
-			chunk_n_cl + chunk_n_sh
+		let gluapack_dir = packer.out_dir.join(format!("gluapack/{}", packer.unique_id()));
+		if dry_run {
+			let (changed, removed) = staging.diff(&gluapack_dir)?;
+			for file_name in changed {
+				let len = staging.len(&file_name)?;
+				quietln!(quiet, "Would write {} ({} bytes)", packer.display_path(&gluapack_dir.join(file_name)), len);
+			}
+			for path in removed {
+				quietln!(quiet, "Would delete {}", packer.display_path(&path));
+			}
 		} else {
-			0
-		};
+			tokio::fs::create_dir_all(packer.out_dir.join("gluapack")).await?;
+			if gluapack_dir.is_dir() {
+				staging.sync(&gluapack_dir)?;
+			} else {
+				staging.persist(&gluapack_dir)?;
+			}
+		}
 
 		quietln!(quiet, "Injecting loader...");
 		packer.write_loader(sv_entry_files, cl_entry_files, sh_entry_files).await?;
 
-		if !in_place && !no_copy {
-			quietln!(quiet, "Deleting unpacked files...");
-			packer.delete_unpacked(sv_paths, cl_paths, sh_paths).await?;
+		if !in_place && !no_copy && !dry_run {
+			// Packed files are never copied, but the directories that only contained them were
+			let out_dir = packer.out_dir.clone();
+			packer.delete_files(&out_dir, sv_paths.into_iter().chain(cl_paths).chain(sh_paths).collect()).await?;
 		}
 
+		if dry_run {
+			return Ok((total_unpacked_files, total_packed_files + 3, started.elapsed()));
+		}
+
+		let mut packed = BTreeSet::new();
+		packed.insert(format!("lua/{}", packer.loader_path()));
+		for entry in std::fs::read_dir(&gluapack_dir)? {
+			packed.insert(relative_path(&out_root, &entry?.path()));
+		}
+
+		PackCache {
+			fingerprint,
+			sources,
+			copied,
+			packed,
+			unique_id: packer.unique_id().to_owned(),
+			unpacked_files: total_unpacked_files,
+			packed_files: total_packed_files + 3
+		}.write(&out_root).await?;
+
 		Ok((total_unpacked_files, total_packed_files + 3, started.elapsed()))
 	}
 
@@ -190,16 +775,17 @@ impl Packer {
 		self.unique_id.as_ref().unwrap()
 	}
 
-	async fn collect_lua_files(&self, patterns: &[GlobPattern], excludes: &[GlobPattern], entries: &[GlobPattern]) -> Result<(HashSet<LuaFile>, Vec<String>), PackingError> {
-		let mut lua_files = HashSet::new();
+	/// Collects the Lua files matching `patterns` and `entries`, sorted by path.
+	///
+	/// Entry files are ordered by the first entry pattern they match, then by path, so that the output is reproducible.
+	async fn collect_lua_files(&self, patterns: &[GlobPattern], excludes: &[GlobPattern], entries: &[GlobPattern]) -> Result<(BTreeSet<LuaFile>, Vec<String>), PackingError> {
+		let mut lua_files = BTreeSet::new();
 		let mut entry_files = vec![];
-		let mut abort_handles = vec![];
-
-		let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<Result<(Vec<u8>, String), std::io::Error>>();
+		let mut pending = vec![];
 
 		for pattern in patterns.iter().chain(entries.iter()) {
 			for path in {
-				util::glob(&self.dir.join(pattern.as_str()).to_string_lossy())
+				util::glob(self.dir.join(pattern.as_str()).to_string_lossy())
 					.expect("Failed to construct glob when joining addon directory")
 					.filter(|result| {
 						match result {
@@ -209,64 +795,57 @@ impl Packer {
 					})
 			} {
 				let fs_path = path?;
-				let path = fs_path.strip_prefix(&self.dir).unwrap().to_string_lossy().into_owned().replace('\\', "/");
-				let tx = tx.clone();
-
+				let path = match fs_path.strip_prefix(&self.dir).unwrap().to_str() {
+					Some(path) => path.replace('\\', "/"),
+					None => return Err(error!(PackingError::UnpackableFile {
+						path: fs_path.strip_prefix(&self.dir).unwrap().to_string_lossy().into_owned(),
+						reason: "its path isn't valid UTF-8"
+					}))
+				};
 				if !lua_files.insert(LuaFile {
 					path: path.to_owned(),
-					contents: vec![]
+					fs_path: fs_path.clone(),
+					len: 0
 				}) {
 					// We've already included this file, skip it.
 					continue;
 				}
 
-				abort_handles.push(
-					tokio::spawn(async move {
-						tx.send(
-							tokio::fs::read(fs_path.clone()).map(|result| {
-								result.map(|contents| {
-									(contents, path)
-								})
-							}).await
-						).ok();
-					})
-				);
+				pending.push((fs_path, path));
 			}
 		}
 
-		drop(tx);
-
-		while let Some(result) = rx.recv().await {
-			let (contents, path) = match result {
-				Ok(data) => data,
-				Err(error) => {
-					abort_handles.into_iter().for_each(|handle| handle.abort());
-					return Err(error!(PackingError::IoError(error)));
-				}
-			};
+		// Only `jobs` files are stat'd at a time, so that huge addons don't run out of file descriptors
+		let mut collected = stream::iter(pending)
+			.map(|(fs_path, path)| async move {
+				let metadata = tokio::fs::metadata(&fs_path).await?;
+				Ok::<_, std::io::Error>((metadata.len(), fs_path, path))
+			})
+			.buffer_unordered(self.jobs);
 
-			for entry in entries {
-				if entry.matches(&path) {
-					entry_files.push(path.to_owned());
-				}
+		while let Some((len, fs_path, path)) = collected.try_next().await? {
+			if let Some(entry_n) = entries.iter().position(|entry| entry.matches(&path)) {
+				entry_files.push((entry_n, path.to_owned()));
 			}
 
 			lua_files.replace(LuaFile {
 				path,
-				contents
+				fs_path,
+				len
 			});
 		}
 
-		Ok((lua_files, entry_files))
+		// Files are collected concurrently, so they arrive in no particular order
+		entry_files.sort_unstable();
+
+		Ok((lua_files, entry_files.into_iter().map(|(_, path)| path).collect()))
 	}
 
-	async fn copy_addon(&self) -> Result<(), std::io::Error> {
+	/// Finds the directories and files to copy from the addon to the output directory.
+	async fn walk_addon(&self) -> Result<AddonFiles, std::io::Error> {
 		let out_dir = self.out_dir.parent().unwrap(); // pop lua/
 
-		tokio::fs::remove_dir_all(out_dir).await?;
-		tokio::fs::create_dir_all(out_dir).await?;
-
-		fn copy_addon(visited_symlinks: &mut HashSet<PathBuf>, from: PathBuf, to: PathBuf) -> Result<(), std::io::Error> {
+		fn walk_addon(visited_symlinks: &mut HashSet<PathBuf>, addon_files: &mut AddonFiles, from: PathBuf, to: PathBuf) -> Result<(), std::io::Error> {
 			#[cfg(target_os = "windows")]
 			const FILE_ATTRIBUTE_HIDDEN: u32 = 0x02;
 
@@ -287,8 +866,8 @@ impl Packer {
 
 				let file_name = entry.file_name().as_ref().unwrap().to_string_lossy();
 
-				if file_name.starts_with(".") || file_name == "gluapack.json" {
-					// Skip hidden files/dirs and gluapack.json
+				if file_name.starts_with(".") || Config::FILE_NAMES.contains(&file_name.as_ref()) {
+					// Skip hidden files/dirs and gluapack config files
 					continue;
 				}
 
@@ -302,10 +881,10 @@ impl Packer {
 
 				if entry.is_dir() {
 					let dir = to.join(&file_name);
-					std::fs::create_dir_all(&dir)?;
-					copy_addon(visited_symlinks, entry, dir)?;
+					addon_files.dirs.push(dir.clone());
+					walk_addon(visited_symlinks, addon_files, entry, dir)?;
 				} else if entry.is_file() {
-					std::fs::copy(entry, to.join(&file_name))?;
+					addon_files.files.push((entry, to.join(&file_name)));
 				}
 			}
 			Ok(())
@@ -316,154 +895,390 @@ impl Packer {
 
 		tokio::task::spawn_blocking(move || {
 			let mut visited_symlinks = HashSet::new();
-			copy_addon(&mut visited_symlinks, from, to)
-		}).await.expect("Failed to join thread")
+			let mut addon_files = AddonFiles { dirs: vec![to.clone()], files: vec![] };
+			walk_addon(&mut visited_symlinks, &mut addon_files, from, to).map(|_| addon_files)
+		}).await?
 	}
 
-	async fn delete_old_gluapack_files(&self) -> Result<(), PackingError> {
-		async fn delete<I, V>(gluapack_dir: I, gluapack_loader: V) -> Result<(), PackingError>
+	async fn copy_addon(&self, addon_files: AddonFiles) -> Result<(), std::io::Error> {
+		let AddonFiles { dirs, mut files } = addon_files;
+
+		if self.dry_run {
+			files.sort_unstable();
+			for (_, to) in files {
+				quietln!(self.quiet, "Would copy {}", self.display_path(&to));
+			}
+			return Ok(());
+		}
+
+		tokio::task::spawn_blocking(move || {
+			for dir in dirs {
+				std::fs::create_dir_all(dir)?;
+			}
+			Ok::<_, std::io::Error>(())
+		}).await??;
+
+		stream::iter(files)
+			.map(|(from, to)| tokio::fs::copy(from, to))
+			.buffer_unordered(self.jobs)
+			.try_for_each(|_| async { Ok(()) })
+			.await
+	}
+
+	/// Checks for files that can't be packed or unpacked, and warns about files that Garry's Mod may not be able to load once they've been unpacked.
+	fn check_lua_files<'a>(&self, lua_files: impl Iterator<Item = &'a LuaFile>) -> Result<(), PackingError> {
+		// Characters that can't be used in file names on Windows, where unpacked files are written to the data folder
+		const INVALID_PATH_CHARS: &[char] = &['<', '>', ':', '"', '\\', '|', '?', '*'];
+
+		for lua_file in lua_files {
+			if lua_file.len > u32::MAX as u64 {
+				return Err(error!(PackingError::UnpackableFile {
+					path: lua_file.path.clone(),
+					reason: "it is 4 GiB or larger"
+				}));
+			}
+
+			if util::escapes_lua_folder(&lua_file.path) {
+				return Err(error!(PackingError::UnpackableFile {
+					path: lua_file.path.clone(),
+					reason: "its path could escape the lua/ folder when it's unpacked"
+				}));
+			}
+
+			if lua_file.path.chars().any(|char| char.is_control() || INVALID_PATH_CHARS.contains(&char)) {
+				quietln!(self.quiet, "WARNING: {:?} contains characters that can't be used in file names on Windows. It will be packed, but won't load on Windows servers or clients.", lua_file.path);
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Fails the pack if a realm has more networked chunks than the config allows, so runaway pack growth is caught before it's shipped.
+	fn check_chunk_limit(&self, realm: &'static str, chunks: usize, max_chunks: Option<usize>) -> Result<(), PackingError> {
+		match max_chunks {
+			Some(max) if chunks > max => Err(error!(PackingError::TooManyChunks { realm, chunks, max })),
+			_ => Ok(())
+		}
+	}
+
+	/// Finds the unique IDs of the packed addons in a `lua/` directory, along with the paths they were found at.
+	fn packed_unique_ids(lua_dir: &Path) -> Vec<(String, PathBuf)> {
+		let file_name = |path: &Path| path.file_name().and_then(|file_name| file_name.to_str()).map(|file_name| file_name.to_owned());
+
+		let gluapack_dir_ids = util::glob(lua_dir.join("gluapack/*").to_string_lossy()).unwrap()
+			.filter_map(|result| result.ok())
+			.filter(|path| path.is_dir())
+			.filter_map(|path| Some((file_name(&path)?, path)));
+
+		let gluapack_loader_ids = util::glob(lua_dir.join("autorun/*_gluapack_*.lua").to_string_lossy()).unwrap()
+			.filter_map(|result| result.ok())
+			.filter_map(|path| Some((file_name(&path)?.split("_gluapack_").next()?.to_owned(), path)));
+
+		gluapack_dir_ids.chain(gluapack_loader_ids).collect()
+	}
+
+	/// Finds the unique IDs of the other packed addons that will still be in the output once this pack has been written, so that we can make sure none of them are using our unique ID.
+	///
+	/// These are the packed addons copied from the addon, and anything else in an output directory that's being updated.
+	/// The previous pack of this addon, and anything else this pack deletes, are left out.
+	///
+	/// This must be called before anything is deleted.
+	fn surviving_unique_ids(dir: &Path, out_root: &Path, in_place: bool, no_copy: bool, previous_cache: Option<&PackCache>) -> Vec<(String, PathBuf)> {
+		let mut unique_ids = vec![];
+
+		// Packing in place deletes every other packed addon in the addon
+		if !in_place {
+			// Everything but the addon's Lua files is copied, including any packed addons in it
+			if !no_copy {
+				unique_ids.extend(Packer::packed_unique_ids(&dir.join("lua")));
+			}
+
+			// Without a cache, the output directory is recreated from scratch
+			if let Some(previous_cache) = previous_cache {
+				unique_ids.extend(
+					Packer::packed_unique_ids(&out_root.join("lua"))
+						.into_iter()
+						.filter(|(unique_id, _)| unique_id != &previous_cache.unique_id)
+				);
+			}
+		}
+
+		unique_ids
+	}
+
+	/// Makes sure none of the packed addons that will survive this pack (see [`Packer::surviving_unique_ids`]) are using `unique_id`.
+	///
+	/// Unique IDs are compared case-insensitively, as Garry's Mod's filesystem is case-insensitive on Windows.
+	fn check_unique_id_collision(unique_id: &str, surviving_unique_ids: &[(String, PathBuf)]) -> Result<(), PackingError> {
+		match surviving_unique_ids.iter().find(|(id, _)| id.eq_ignore_ascii_case(unique_id)) {
+			Some((_, path)) => Err(error!(PackingError::UniqueIdCollision {
+				unique_id: unique_id.to_owned(),
+				path: path.display().to_string()
+			})),
+			None => Ok(())
+		}
+	}
+
+	/// Deletes any packed addons in the addon, except the previous pack of this addon (`keep`), which is updated in place.
+	async fn delete_old_gluapack_files(&self, keep: Option<&str>) -> Result<(), PackingError> {
+		async fn delete<I, V>(packer: &Packer, gluapack_dir: I, gluapack_loader: V) -> Result<(), PackingError>
 		where
 			I: Iterator<Item = Result<PathBuf, glob::GlobError>>,
 			V: Iterator<Item = Result<PathBuf, glob::GlobError>>
 		{
 			for gluapack_loader in gluapack_loader {
-				tokio::fs::remove_file(gluapack_loader?).await?;
+				let gluapack_loader = gluapack_loader?;
+				if packer.dry_run {
+					quietln!(packer.quiet, "Would delete {}", packer.display_path(&gluapack_loader));
+				} else {
+					tokio::fs::remove_file(gluapack_loader).await?;
+				}
 			}
 			for gluapack_dir in gluapack_dir {
-				tokio::fs::remove_dir_all(gluapack_dir?).await?;
+				let gluapack_dir = gluapack_dir?;
+				if packer.dry_run {
+					quietln!(packer.quiet, "Would delete {}/", packer.display_path(&gluapack_dir));
+				} else {
+					tokio::fs::remove_dir_all(gluapack_dir).await?;
+				}
 			}
 			Ok(())
 		}
 
+		let loader_file_name = keep.map(|keep| format!("{}_gluapack_{}.lua", keep, env!("CARGO_PKG_VERSION")));
+		let is_kept = |path: &PathBuf| match (keep, path.file_name().and_then(|file_name| file_name.to_str())) {
+			(Some(keep), Some(file_name)) => file_name == keep || Some(file_name) == loader_file_name.as_deref(),
+			_ => false
+		};
+
 		if !self.quiet {
-			let mut gluapack_dir = util::glob(&self.out_dir.join("gluapack/*").to_string_lossy()).unwrap()
+			let mut gluapack_dir = util::glob(self.out_dir.join("gluapack/*").to_string_lossy()).unwrap()
 				.filter(|result| match result {
-					Ok(path) => path.is_dir(),
+					Ok(path) => path.is_dir() && !is_kept(path),
 					Err(_) => true
 				})
 				.peekable();
 
-			let mut gluapack_loader = util::glob(&self.out_dir.join("autorun/*_gluapack_*.lua").to_string_lossy()).unwrap().peekable();
+			let mut gluapack_loader = util::glob(self.out_dir.join("autorun/*_gluapack_*.lua").to_string_lossy()).unwrap()
+				.filter(|result| !matches!(result, Ok(path) if is_kept(path)))
+				.peekable();
 
 			if gluapack_dir.peek().is_some() || gluapack_loader.peek().is_some() {
 				println!("Deleting old gluapack files...");
-				delete(gluapack_dir, gluapack_loader).await?;
+				delete(self, gluapack_dir, gluapack_loader).await?;
 			} else {
 				return Ok(());
 			}
 		} else {
-			let gluapack_dir = util::glob(&self.out_dir.join("gluapack/*").to_string_lossy()).unwrap()
+			let gluapack_dir = util::glob(self.out_dir.join("gluapack/*").to_string_lossy()).unwrap()
 				.filter(|result| match result {
-					Ok(path) => path.is_dir(),
+					Ok(path) => path.is_dir() && !is_kept(path),
 					Err(_) => true
 				});
 
-			let gluapack_loader = util::glob(&self.out_dir.join("autorun/*_gluapack_*.lua").to_string_lossy()).unwrap();
+			let gluapack_loader = util::glob(self.out_dir.join("autorun/*_gluapack_*.lua").to_string_lossy()).unwrap()
+				.filter(|result| !matches!(result, Ok(path) if is_kept(path)));
 
-			delete(gluapack_dir, gluapack_loader).await?;
+			delete(self, gluapack_dir, gluapack_loader).await?;
 		};
 
 		Ok(())
 	}
 
-	fn pack_lua_files(lua_files: HashSet<LuaFile>, is_sent_to_client: bool) -> (Vec<String>, Vec<u8>) {
-		use std::io::Write;
+	/// LZMA compresses the entries of `lua_files` into a temporary file in the staging directory, returning it rewound along with its length.
+	///
+	/// In a dry run, they're compressed into memory instead. Either way, [`Staging::remove`] must be called with `file_name` once they've been read.
+	fn compress_entries(lua_files: &BTreeSet<LuaFile>, is_sent_to_client: bool, staging: &Staging, file_name: &str) -> Result<(Box<dyn Read>, u64), PackingError> {
+		fn compress<F: Read + Write + Seek>(lua_files: &BTreeSet<LuaFile>, is_sent_to_client: bool, file: F) -> Result<(F, u64), PackingError> {
+			let uncompressed_len = lua_files.iter().map(|lua_file| lua_file.packed_len(is_sent_to_client)).sum();
 
-		let mut file_list = Vec::with_capacity(lua_files.len());
+			let mut encoder = util::lzma_encoder(BufWriter::new(file), uncompressed_len);
+			for lua_file in lua_files.iter() {
+				lua_file.write_entry(&mut encoder, is_sent_to_client)?;
+			}
 
-		let mut superchunk: Vec<u8> = Vec::with_capacity((lua_files.len() * MAX_LUA_SIZE).min(MEM_PREALLOCATE_MAX));
-		for mut lua_file in lua_files.into_iter() {
-			superchunk.reserve_exact(lua_file.contents.len() + lua_file.path.len() + 4);
+			let mut file = encoder.finish()?.into_inner().map_err(|error| error.into_error())?;
+			let len = file.stream_position()?;
+			util::lzma_set_uncompressed_size(&mut file, uncompressed_len)?;
 
-			superchunk.write_all(&mut lua_file.path.as_bytes()).expect("Failed to write script path into superchunk");
-			if is_sent_to_client {
-				// We can't use NUL to terminate because clientside Lua files will only send up to the NUL byte (fucking C strings)
-				// We can just use a | instead
-				superchunk.push(TERMINATOR_HACK);
+			Ok((file, len))
+		}
 
-				// Write the length of the file as a hex string since we can't use NUL to terminate
-				superchunk.write_all(format!("{:x}", lua_file.contents.len()).as_bytes()).expect("Failed to write Lua file length into superchunk");
-				superchunk.push(TERMINATOR_HACK);
-			} else {
-				superchunk.push(0);
+		match staging {
+			Staging::Dir(dir) => {
+				let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(dir.join(file_name))?;
+				let (file, len) = compress(lua_files, is_sent_to_client, file)?;
+				Ok((Box::new(file), len))
+			},
+			Staging::Planned(_) => {
+				let (compressed, len) = compress(lua_files, is_sent_to_client, Cursor::new(Vec::new()))?;
+				Ok((Box::new(compressed), len))
+			}
+		}
+	}
+
+	/// Writes the serverside Lua files into a single superchunk, `gluapack.sv.lua`, preceded by its [`PackHeader`]. Returns the hash of the superchunk.
+	///
+	/// If the pack is compressed, the header is followed by the LZMA compressed entries. The serverside pack is never networked, so it isn't base64 encoded.
+	fn write_sv_pack(lua_files: &BTreeSet<LuaFile>, compression: Compression, staging: &Staging) -> Result<Vec<u8>, PackingError> {
+		if lua_files.is_empty() {
+			return Ok(sha2::Sha256::digest(b"").to_vec());
+		}
+
+		let mut superchunk = HashingWriter {
+			inner: staging.create("gluapack.sv.lua")?,
+			sha256: sha2::Sha256::new()
+		};
+
+		if compression == Compression::Lzma {
+			let (mut compressed, length) = Packer::compress_entries(lua_files, false, staging, "sv.lzma")?;
+
+			superchunk.write_all(&PackHeader::new("sv", lua_files.len(), length, compression).to_bytes())?;
+			std::io::copy(&mut compressed, &mut superchunk)?;
+
+			drop(compressed);
+			staging.remove("sv.lzma")?;
+		} else {
+			let length = lua_files.iter().map(|lua_file| lua_file.packed_len(false)).sum();
+			superchunk.write_all(&PackHeader::new("sv", lua_files.len(), length, compression).to_bytes())?;
+			for lua_file in lua_files.iter() {
+				lua_file.write_entry(&mut superchunk, false)?;
+			}
+		}
 
-				debug_assert_eq!((lua_file.contents.len() as u32).to_le_bytes().len(), 4);
-				for byte in (lua_file.contents.len() as u32).to_le_bytes().iter() {
-					superchunk.push(*byte);
+		staging.finish(superchunk.inner)?;
+		Ok(superchunk.sha256.finalize().to_vec())
+	}
+
+	/// Packs networked Lua files into chunks that are small enough to be sent to the client once commented by [`comment::commentify`], passing each chunk to `emit`.
+	///
+	/// Chunks are cut on file boundaries where possible, and a new chunk is always started at an "anchor" file (picked by a hash of its path).
+	/// A change to one file can therefore only move the chunk boundaries up until the next anchor, so unchanged files stay in unchanged chunks
+	/// and clients don't have to download the whole pack again.
+	///
+	/// If the pack is compressed, the compressed entries are simply cut into chunks, so this doesn't apply.
+	///
+	/// No chunk will be larger than `chunk_size` once commented.
+	fn chunk_lua_files(lua_files: &BTreeSet<LuaFile>, realm: &'static str, compression: Compression, chunk_size: usize, staging: &Staging, emit: impl FnMut(Vec<u8>) -> Result<(), PackingError>) -> Result<(), PackingError> {
+		if lua_files.is_empty() {
+			return Ok(());
+		}
+
+		if compression == Compression::Lzma {
+			let compressed_file_name = format!("{}.lzma", realm);
+			let (mut compressed, compressed_len) = Packer::compress_entries(lua_files, true, staging, &compressed_file_name)?;
+			let encoded_len = compressed_len.div_ceil(3) * 4;
+
+			// Base64 contains no NUL bytes or closing brackets, so it doesn't need escaping and can be cut anywhere
+			let mut chunker = Chunker::new(PackHeader::new(realm, lua_files.len(), encoded_len, compression).to_bytes(), chunk_size, emit);
+
+			// Blocks are a multiple of 3 bytes long, so encoding them one at a time is the same as encoding them all at once
+			const BLOCK_SIZE: u64 = 3 * 16 * 1024;
+			let mut block = Vec::with_capacity(BLOCK_SIZE as usize);
+			loop {
+				block.clear();
+				(&mut compressed).take(BLOCK_SIZE).read_to_end(&mut block)?;
+				if block.is_empty() {
+					break;
 				}
+				chunker.push(base64::encode(&block).as_bytes())?;
 			}
+			chunker.finish()?;
+
+			drop(compressed);
+			staging.remove(&compressed_file_name)?;
+
+			return Ok(());
+		}
+
+		let length = lua_files.iter().map(|lua_file| lua_file.packed_len(true)).sum();
+		let mut chunker = Chunker::new(PackHeader::new(realm, lua_files.len(), length, compression).to_bytes(), chunk_size, emit);
 
-			superchunk.write_all(&mut lua_file.contents).expect("Failed to write Lua file into superchunk");
+		let mut escaped = Vec::new();
+		for lua_file in lua_files.iter() {
+			escaped.clear();
+			escape::escape(&mut escaped, &lua_file.entry_prefix(true));
 
-			file_list.push(lua_file.path);
+			if lua_file.len <= chunker.max_len() as u64 {
+				// Small files are read whole, so we know whether they fit in the chunk being filled
+				escape::escape(&mut escaped, &lua_file.read()?);
+				if chunker.has_entries() && ((chunker.is_anchorable() && is_chunk_anchor(&lua_file.path)) || chunker.chunk_len() + escaped.len() > chunker.max_len()) {
+					chunker.cut()?;
+				}
+				chunker.push(&escaped)?;
+			} else {
+				// Files that are too big for a single chunk always start a new chunk, and are streamed across several
+				if chunker.has_entries() {
+					chunker.cut()?;
+				}
+				chunker.push(&escaped)?;
+				lua_file.read_blocks(|block| {
+					escaped.clear();
+					escape::escape(&mut escaped, block);
+					chunker.push(&escaped)
+				})?;
+			}
 		}
 
-		(file_list, superchunk)
+		chunker.finish()
 	}
 
-	async fn write_packed_chunks(&self, bytes: Vec<u8>, chunk_name: &'static str) -> Result<(Vec<[u8; 20]>, usize), PackingError> {
-		use tokio::io::AsyncWriteExt;
+	/// Chunks a realm's networked Lua files, and writes the chunks to the staging area.
+	///
+	/// Returns the hashes the client's Lua cache will use for each chunk, and the hash of the chunks' contents.
+	fn write_networked_chunks(lua_files: &BTreeSet<LuaFile>, realm: &'static str, compression: Compression, chunk_size: usize, staging: &Staging) -> Result<(Vec<[u8; 20]>, Vec<u8>), PackingError> {
+		let mut hashes = Vec::new();
+		let mut sha256 = sha2::Sha256::new();
 
-		let gluapack_dir = self.out_dir.join(format!("gluapack/{}", self.unique_id()));
+		Packer::chunk_lua_files(lua_files, realm, compression, chunk_size, staging, |chunk| {
+			sha256.update(&chunk);
 
-		let is_sent_to_client = matches!(chunk_name, "sh" | "cl");
-		if is_sent_to_client {
-			let mut chunk_n = 0;
-
-			let hashes = future::try_join_all(
-				commentify(bytes).chunks(MAX_LUA_SIZE).enumerate().map(|(i, chunk)| {
-					chunk_n += 1;
-					let file_name = format!("gluapack.{}.{}.lua", i + 1, chunk_name);
-					let path = gluapack_dir.join(&file_name);
-					async move {
-						if !chunk.starts_with(COMMENT_START) {
-							let mut f = tokio::fs::File::create(&path).await?;
-							f.write_all(COMMENT_START).await?;
-							f.write_all(&chunk).await?;
-
-							Result::<[u8; 20], std::io::Error>::Ok({
-								let mut sha256 = sha2::Sha256::new();
-								sha256.update(COMMENT_START);
-								sha256.update(chunk);
-								sha256.update(&[0u8]);
-
-								let sha256 = sha256.finalize();
-								sha256[0..20].try_into().unwrap()
-							})
-						} else {
-							tokio::fs::write(&path, &chunk).await?;
-
-							Result::<[u8; 20], std::io::Error>::Ok({
-								let mut sha256 = sha2::Sha256::new();
-								sha256.update(chunk);
-								sha256.update(&[0u8]);
-
-								let sha256 = sha256.finalize();
-								sha256[0..20].try_into().unwrap()
-							})
-						}
-					}
-				})
-			).await?;
+			let file_name = format!("gluapack.{}.{}.lua", hashes.len() + 1, realm);
+			let chunk = comment::commentify(&chunk);
 
-			Ok((hashes, chunk_n))
-		} else {
-			let mut chunk_n = 0;
-
-			future::try_join_all(
-				bytes.chunks(MAX_LUA_SIZE).enumerate().map(|(i, chunk)| {
-					chunk_n += 1;
-					let file_name = format!("gluapack.{}.{}.lua", i + 1, chunk_name);
-					let path = gluapack_dir.join(&file_name);
-					tokio::fs::write(path, chunk)
-				})
-			).await?;
+			// The client compiles this chunk to get it into the Lua cache, so it must never contain any live code
+			if !comment::is_inert(&chunk) {
+				return Err(error!(PackingError::UnsafeChunk(file_name)));
+			}
 
-			Ok((vec![], chunk_n))
+			staging.write(&file_name, &chunk)?;
+
+			hashes.push(lua_cache_hash(&chunk));
+
+			Ok(())
+		})?;
+
+		Ok((hashes, sha256.finalize().to_vec()))
+	}
+
+	/// Counts the chunks a realm's networked Lua files would be packed into, without writing them.
+	fn count_networked_chunks(lua_files: &BTreeSet<LuaFile>, realm: &'static str, compression: Compression, chunk_size: usize, staging: &Staging) -> Result<usize, PackingError> {
+		let mut chunks = 0;
+		Packer::chunk_lua_files(lua_files, realm, compression, chunk_size, staging, |_| {
+			chunks += 1;
+			Ok(())
+		})?;
+		Ok(chunks)
+	}
+
+	/// Reads the chunk hashes from any cache manifests left in the output directory by a previous pack.
+	fn previous_chunk_hashes(out_dir: &Path) -> HashSet<String> {
+		let mut hashes = HashSet::new();
+		for manifest in util::glob(out_dir.join("lua/gluapack/*/manifest.lua").to_string_lossy()).unwrap().filter_map(|result| result.ok()) {
+			if let Ok(manifest) = std::fs::read_to_string(manifest) {
+				hashes.extend(
+					manifest.split('"')
+						.skip(1).step_by(2)
+						.filter(|hash| hash.len() == 40 && hash.bytes().all(|byte| byte.is_ascii_hexdigit()))
+						.map(|hash| hash.to_owned())
+				);
+			}
 		}
+		hashes
 	}
 
-	async fn generate_cache_manifest(&self, hashes_cl: Vec<[u8; 20]>, hashes_sh: Vec<[u8; 20]>) -> Result<(), PackingError> {
+	/// Writes the manifest of the networked chunks' Lua cache hashes to the staging area.
+	fn generate_cache_manifest(&self, staging: &Staging, hashes_cl: &[[u8; 20]], hashes_sh: &[[u8; 20]]) -> Result<(), PackingError> {
 		let mut cache_manifest = String::new();
 		cache_manifest.push_str("return{");
 
@@ -471,10 +1286,7 @@ impl Packer {
 			cache_manifest.push_str("sh={");
 			for hash in hashes_sh {
 				cache_manifest.push('"');
-				cache_manifest.reserve(40);
-				for byte in hash.iter() {
-					cache_manifest.push_str(&format!("{:02x}", byte));
-				}
+				cache_manifest.push_str(&hash_hex(hash));
 				cache_manifest.push('"');
 				cache_manifest.push(',');
 			}
@@ -487,10 +1299,7 @@ impl Packer {
 			cache_manifest.push_str("cl={");
 			for hash in hashes_cl {
 				cache_manifest.push('"');
-				cache_manifest.reserve(40);
-				for byte in hash.iter() {
-					cache_manifest.push_str(&format!("{:02x}", byte));
-				}
+				cache_manifest.push_str(&hash_hex(hash));
 				cache_manifest.push('"');
 				cache_manifest.push(',');
 			}
@@ -499,13 +1308,13 @@ impl Packer {
 		}
 
 		cache_manifest.push('}');
-		tokio::fs::write(self.out_dir.join(format!("gluapack/{}/manifest.lua", self.unique_id())), cache_manifest).await?;
+		staging.write("manifest.lua", cache_manifest.as_bytes())?;
 
 		Ok(())
 	}
 
 	async fn write_loader(&self, sv_entry_files: Vec<String>, cl_entry_files: Vec<String>, sh_entry_files: Vec<String>) -> Result<(), PackingError> {
-		const GLUAPACK_LOADER: &'static str = include_str!("gluapack.lua");
+		const GLUAPACK_LOADER: &str = include_str!("gluapack.lua");
 
 		fn join_entry_files(entry_files: Vec<String>) -> String {
 			if entry_files.is_empty() {
@@ -529,44 +1338,89 @@ impl Packer {
 			tokio::task::spawn_blocking(move || join_entry_files(sv_entry_files)),
 			tokio::task::spawn_blocking(move || join_entry_files(cl_entry_files)),
 			tokio::task::spawn_blocking(move || join_entry_files(sh_entry_files)),
-		).expect("Failed to join threads");
+		).map_err(std::io::Error::from)?;
 
 		let loader = GLUAPACK_LOADER
 			.replacen("{ENTRY_FILES_SV}", &sv_entry_files, 1)
 			.replacen("{ENTRY_FILES_CL}", &cl_entry_files, 1)
-			.replacen("{ENTRY_FILES_SH}", &sh_entry_files, 1);
+			.replacen("{ENTRY_FILES_SH}", &sh_entry_files, 1)
+			.replacen("{PACK_FORMAT_VERSION}", &PACK_FORMAT_VERSION.to_string(), 1);
 
-		tokio::fs::create_dir_all(self.out_dir.join("autorun")).await?;
-		tokio::fs::write(self.out_dir.join(format!("autorun/{}_gluapack_{}.lua", self.unique_id(), env!("CARGO_PKG_VERSION"))), loader).await?;
+		self.write_output(&self.out_dir.join(self.loader_path()), loader.as_bytes()).await?;
 
 		Ok(())
 	}
 
-	async fn delete_unpacked(&self, sv_paths: Vec<String>, cl_paths: Vec<String>, sh_paths: Vec<String>) -> Result<(), PackingError> {
+	/// Formats the path of an output file relative to the output directory, for printing.
+	fn display_path(&self, path: &Path) -> String {
+		relative_path(self.out_dir.parent().unwrap(), path)
+	}
+
+	/// Writes an output file, unless it hasn't changed. In a dry run, this just reports whether it would be written.
+	async fn write_output(&self, path: &Path, contents: &[u8]) -> Result<(), PackingError> {
+		if self.dry_run {
+			if tokio::fs::read(path).await.map(|existing| existing != contents).unwrap_or(true) {
+				quietln!(self.quiet, "Would write {} ({} bytes)", self.display_path(path), contents.len());
+			}
+		} else {
+			if let Some(parent) = path.parent() {
+				tokio::fs::create_dir_all(parent).await?;
+			}
+			util::write_if_changed(path, contents).await?;
+		}
+		Ok(())
+	}
+
+	/// The path of the loader, relative to the output directory's lua/ folder.
+	fn loader_path(&self) -> String {
+		format!("autorun/{}_gluapack_{}.lua", self.unique_id(), env!("CARGO_PKG_VERSION"))
+	}
+
+	/// Deletes files relative to `root`, and any directories inside `root` that are left empty.
+	async fn delete_files(&self, root: &Path, paths: Vec<String>) -> Result<(), PackingError> {
+		if self.dry_run {
+			for path in paths {
+				let path = root.join(path);
+				if path.exists() {
+					quietln!(self.quiet, "Would delete {}", self.display_path(&path));
+				}
+			}
+			return Ok(());
+		}
+
 		let mut check_empty = Vec::new();
 
-		future::try_join_all(
-			sv_paths.into_iter().chain(cl_paths.into_iter()).chain(sh_paths.into_iter()).map(|path| {
-				let path = self.out_dir.join(path);
-				for ancestor in path.ancestors().skip(1) {
-					if ancestor == self.out_dir {
-						break;
-					} else {
-						let ancestor = ancestor.to_path_buf();
-						if let Err(pos) = check_empty.binary_search_by(|probe: &PathBuf| probe.cmp(&ancestor).reverse()) {
-							check_empty.insert(pos, ancestor);
-						}
+		let paths = paths.into_iter().map(|path| {
+			let path = root.join(path);
+			for ancestor in path.ancestors().skip(1) {
+				if ancestor == root {
+					break;
+				} else {
+					let ancestor = ancestor.to_path_buf();
+					if let Err(pos) = check_empty.binary_search_by(|probe: &PathBuf| probe.cmp(&ancestor).reverse()) {
+						check_empty.insert(pos, ancestor);
 					}
 				}
-				tokio::fs::remove_file(path)
+			}
+			path
+		}).collect::<Vec<_>>();
+
+		stream::iter(paths)
+			.map(|path| async move {
+				match tokio::fs::remove_file(path).await {
+					Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error),
+					_ => Ok(())
+				}
 			})
-		).await?;
+			.buffer_unordered(self.jobs)
+			.try_for_each(|_| async { Ok(()) })
+			.await?;
 
 		tokio::task::spawn_blocking(move || {
 			for dir in check_empty {
 				std::fs::remove_dir(dir).ok();
 			}
-		}).await.expect("Failed to join thread");
+		}).await.map_err(std::io::Error::from)?;
 
 		Ok(())
 	}
@@ -581,13 +1435,44 @@ pub enum PackingError {
 		backtrace: std::backtrace::Backtrace
 	},
 
-	#[error("gluapack.json error: {error}")]
+	#[error("{} error: {error}", path.display())]
 	ConfigError {
+		path: PathBuf,
 		error: serde_json::Error,
 		#[cfg(all(debug_assertions, feature = "nightly"))]
 		backtrace: std::backtrace::Backtrace
 	},
 
+	#[error("{} error: {error}", path.display())]
+	TomlConfigError {
+		path: PathBuf,
+		error: toml::de::Error,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("{} error: {error}", path.display())]
+	YamlConfigError {
+		path: PathBuf,
+		error: serde_yaml::Error,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("Config error: {error}")]
+	ConfigExtendsError {
+		error: String,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("Found multiple config files in your addon: {error}\nPlease delete all but one of them.")]
+	ConfigConflict {
+		error: String,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
 	#[error("Realm conflict! This file is included in multiple realms: {error}\nPlease tinker your config and resolve the realm conflicts.")]
 	RealmConflict {
 		error: String,
@@ -595,6 +1480,52 @@ pub enum PackingError {
 		backtrace: std::backtrace::Backtrace
 	},
 
+	#[error("Invalid unique_id {error:?} in your config. It must be a non-empty string of ASCII letters and numbers.")]
+	InvalidUniqueId {
+		error: String,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("Another packed addon ({path}) is already using the unique_id {unique_id:?}\nPlease change the unique_id in your config.")]
+	UniqueIdCollision {
+		unique_id: String,
+		path: String,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("Refusing to write {error} because it isn't entirely commented out, so clients would run its contents as code. This is a bug in gluapack, please report it!")]
+	UnsafeChunk {
+		error: String,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("{path:?} can't be packed because {reason}")]
+	UnpackableFile {
+		path: String,
+		reason: &'static str,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("chunk_size {error} is invalid. It must be between {} and {} bytes (Garry's Mod won't network Lua files larger than {} bytes)", MIN_LUA_SIZE, MAX_LUA_SIZE, MAX_LUA_SIZE)]
+	InvalidChunkSize {
+		error: usize,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("Packing produced {chunks} {realm} chunk(s), but max_chunks_{realm} in your config only allows {max}")]
+	TooManyChunks {
+		realm: &'static str,
+		chunks: usize,
+		max: usize,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
 	#[error("No Lua files were found in your addon using this inclusion configuration")]
 	NoLuaFiles {
 		#[cfg(all(debug_assertions, feature = "nightly"))]
@@ -602,7 +1533,6 @@ pub enum PackingError {
 	},
 }
 impl_error!(std::io::Error, PackingError::IoError);
-impl_error!(serde_json::Error, PackingError::ConfigError);
 impl From<glob::GlobError> for PackingError {
 	fn from(error: glob::GlobError) -> Self {
 		Self::IoError {
@@ -612,3 +1542,222 @@ impl From<glob::GlobError> for PackingError {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Writes `count` Lua files of varying sizes to a fresh directory, returning them ready to be packed.
+	///
+	/// The files contain no bytes that need escaping and no closing brackets, so their packed length is easy to work out.
+	fn lua_files(name: &str, count: usize) -> (util::TempDir, BTreeSet<LuaFile>) {
+		let dir = util::TempDir::new(name);
+
+		let mut lua_files = BTreeSet::new();
+		for i in 0..count {
+			let path = format!("myaddon/cl_file{}.lua", i);
+			let fs_path = dir.join(format!("cl_file{}.lua", i));
+			let len = 200 + (sha2::Sha256::digest(path.as_bytes())[0] as usize) * 40;
+			std::fs::write(&fs_path, "-".repeat(len)).unwrap();
+			lua_files.insert(LuaFile { path, fs_path, len: len as u64 });
+		}
+		(dir, lua_files)
+	}
+
+	/// Counts the chunks that filling each chunk as full as possible, without any anchors, would produce.
+	fn greedy_chunks(lua_files: &BTreeSet<LuaFile>, chunk_size: usize) -> usize {
+		let max_len = chunk_size - comment::RESERVED_OVERHEAD;
+		let length = lua_files.iter().map(|lua_file| lua_file.packed_len(true)).sum();
+		let header_len = PackHeader::new("cl", lua_files.len(), length, Compression::None).to_bytes().len();
+
+		let mut chunks = 1;
+		let mut chunk_len = header_len;
+		for lua_file in lua_files {
+			let len = lua_file.packed_len(true) as usize;
+			if chunk_len > header_len && chunk_len + len > max_len {
+				chunks += 1;
+				chunk_len = 0;
+			}
+			chunk_len += len;
+		}
+		chunks
+	}
+
+	#[test]
+	fn anchors_dont_inflate_chunk_count() {
+		let (_dir, lua_files) = lua_files("anchors", 400);
+
+		for chunk_size in [MIN_LUA_SIZE * 8, MAX_LUA_SIZE] {
+			let chunks = Packer::count_networked_chunks(&lua_files, "cl", Compression::None, chunk_size, &Staging::planned()).unwrap();
+			let greedy = greedy_chunks(&lua_files, chunk_size);
+
+			// Anchors only cut chunks that are at least 3/4 full, so there can only be about a third more chunks than greedy packing
+			assert!(chunks >= greedy);
+			assert!(chunks <= greedy + greedy / 3 + 1, "{} chunks, but greedy packing needs {} (chunk_size {})", chunks, greedy, chunk_size);
+		}
+	}
+
+	/// Writes an addon with the given unique ID to `myaddon/` in a fresh directory, returning the directory.
+	fn addon(name: &str, unique_id: &str) -> util::TempDir {
+		let root = util::TempDir::new(name);
+		std::fs::create_dir_all(root.join("myaddon/lua/autorun")).unwrap();
+		std::fs::write(root.join("myaddon/gluapack.json"), format!("{{\"unique_id\": \"{}\"}}", unique_id)).unwrap();
+		std::fs::write(root.join("myaddon/lua/autorun/sh_init.lua"), "print(\"hello\")").unwrap();
+		root
+	}
+
+	async fn pack(root: &Path, in_place: bool, no_cache: bool, dry_run: bool) -> Result<(usize, usize, Duration), PackingError> {
+		let out_dir = if in_place { None } else { Some(root.join("myaddon-packed")) };
+		Packer::pack(root.join("myaddon"), out_dir, false, None, 4, no_cache, false, dry_run, true).await
+	}
+
+	/// Reads every file in a directory, recursively.
+	fn snapshot(dir: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+		let mut files = BTreeMap::new();
+		for entry in std::fs::read_dir(dir).unwrap() {
+			let path = entry.unwrap().path();
+			if path.is_dir() {
+				files.extend(snapshot(&path));
+			} else {
+				files.insert(path.clone(), std::fs::read(path).unwrap());
+			}
+		}
+		files
+	}
+
+	fn assert_collision(result: Result<(usize, usize, Duration), PackingError>, expected_unique_id: &str) {
+		match result {
+			Err(PackingError::UniqueIdCollision { unique_id, path, .. }) => {
+				assert_eq!(unique_id, expected_unique_id);
+				assert!(path.contains("gluapack"), "{}", path);
+			},
+			result => panic!("expected a unique_id collision, got {:?}", result.map(|_| ()))
+		}
+	}
+
+	#[tokio::test]
+	async fn unique_id_collides_with_other_addons() {
+		let root = addon("collision", "myaddon");
+		pack(&root, false, false, false).await.unwrap();
+		let before = snapshot(&root);
+
+		// Packed addons in the addon are copied to the output
+		std::fs::create_dir_all(root.join("myaddon/lua/gluapack/shared")).unwrap();
+		std::fs::write(root.join("myaddon/gluapack.json"), r#"{"unique_id": "Shared"}"#).unwrap();
+		assert_collision(pack(&root, false, true, false).await, "Shared");
+		std::fs::write(root.join("myaddon/gluapack.json"), r#"{"unique_id": "myaddon"}"#).unwrap();
+		std::fs::remove_dir(root.join("myaddon/lua/gluapack/shared")).unwrap();
+
+		// So are packed addons that are already in an output directory that's being updated, even if nothing has changed
+		std::fs::create_dir_all(root.join("myaddon-packed/lua/gluapack/MyAddon")).unwrap();
+		assert_collision(pack(&root, false, false, false).await, "myaddon");
+		std::fs::remove_dir(root.join("myaddon-packed/lua/gluapack/MyAddon")).unwrap();
+
+		// Nothing is deleted when the pack collides
+		assert_eq!(before, snapshot(&root));
+
+		// Other addons next to the output aren't in it
+		std::fs::create_dir_all(root.join("otheraddon/lua/gluapack/myaddon")).unwrap();
+		pack(&root, false, true, false).await.unwrap();
+		pack(&root, true, true, false).await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn hashed_unique_id_collision_deletes_nothing() {
+		let root = addon("hashed-collision", "myaddon");
+		std::fs::write(root.join("myaddon/gluapack.json"), "{}").unwrap();
+		pack(&root, false, false, false).await.unwrap();
+
+		let unique_id = std::fs::read_dir(root.join("myaddon-packed/lua/gluapack")).unwrap().next().unwrap().unwrap().file_name();
+		let unique_id = unique_id.to_str().unwrap();
+		let before = snapshot(&root);
+
+		// Previous packs are excluded from packing, so this doesn't change the hash
+		std::fs::create_dir_all(root.join("myaddon/lua/gluapack").join(unique_id)).unwrap();
+
+		// Whether the output directory would be recreated, updated, or left alone because nothing has changed
+		assert_collision(pack(&root, false, true, false).await, unique_id);
+		assert_collision(pack(&root, false, false, false).await, unique_id);
+		std::fs::write(root.join("myaddon/readme.txt"), "Files that aren't packed don't change the hash").unwrap();
+		assert_collision(pack(&root, false, false, false).await, unique_id);
+		std::fs::remove_file(root.join("myaddon/readme.txt")).unwrap();
+
+		std::fs::remove_dir(root.join("myaddon/lua/gluapack").join(unique_id)).unwrap();
+		assert_eq!(before, snapshot(&root));
+	}
+
+	#[tokio::test]
+	async fn side_by_side_builds_dont_collide() {
+		let root = addon("side-by-side", "myaddon");
+		std::fs::write(root.join("myaddon/gluapack.json"), "{}").unwrap();
+
+		for out_dir in ["d1", "d2", "d1", "d2"] {
+			Packer::pack(root.join("myaddon"), Some(root.join(out_dir)), false, None, 4, true, false, false, true).await.unwrap();
+		}
+	}
+
+	#[tokio::test]
+	async fn unique_id_doesnt_collide_with_previous_pack() {
+		for in_place in [false, true] {
+			let root = addon(if in_place { "repack-in-place" } else { "repack" }, "myaddon");
+
+			pack(&root, in_place, false, false).await.unwrap();
+			pack(&root, in_place, true, true).await.unwrap();
+			pack(&root, in_place, true, false).await.unwrap();
+		}
+	}
+
+	#[tokio::test]
+	async fn chunk_limit_deletes_nothing() {
+		for in_place in [false, true] {
+			let root = addon(if in_place { "chunk-limit-in-place" } else { "chunk-limit" }, "myaddon");
+			pack(&root, in_place, false, false).await.unwrap();
+			let before = snapshot(&root);
+
+			std::fs::write(root.join("myaddon/gluapack.json"), r#"{"unique_id": "myaddon", "max_chunks_sh": 0}"#).unwrap();
+			for no_cache in [false, true] {
+				match pack(&root, in_place, no_cache, false).await {
+					Err(PackingError::TooManyChunks { realm: "sh", chunks: 1, max: 0, .. }) => {},
+					result => panic!("expected too many chunks, got {:?}", result.map(|_| ()))
+				}
+			}
+
+			std::fs::write(root.join("myaddon/gluapack.json"), r#"{"unique_id": "myaddon"}"#).unwrap();
+			assert_eq!(before, snapshot(&root));
+		}
+	}
+
+	#[test]
+	fn planned_staging_diff() {
+		let dir = util::TempDir::new("planned");
+		std::fs::write(dir.join("unchanged.lua"), "same").unwrap();
+		std::fs::write(dir.join("changed.lua"), "before").unwrap();
+		std::fs::write(dir.join("same-length.lua"), "before").unwrap();
+		std::fs::write(dir.join("removed.lua"), "gone").unwrap();
+
+		let staging = Staging::planned();
+		staging.write("unchanged.lua", b"same").unwrap();
+		staging.write("changed.lua", b"after!!").unwrap();
+		staging.write("same-length.lua", b"after!").unwrap();
+		staging.write("added.lua", b"new").unwrap();
+
+		let (changed, removed) = staging.diff(&dir).unwrap();
+		assert_eq!(changed, ["added.lua", "changed.lua", "same-length.lua"]);
+		assert_eq!(removed, [dir.join("removed.lua")]);
+		assert_eq!(staging.len(OsStr::new("changed.lua")).unwrap(), 7);
+	}
+
+	#[tokio::test]
+	async fn dry_run_doesnt_write() {
+		for in_place in [false, true] {
+			let root = addon(if in_place { "dry-run-in-place" } else { "dry-run" }, "myaddon");
+			pack(&root, in_place, false, false).await.unwrap();
+			std::fs::write(root.join("myaddon/lua/autorun/sh_init.lua"), "print(\"changed\")").unwrap();
+
+			let before = snapshot(&root);
+			pack(&root, in_place, false, true).await.unwrap();
+			pack(&root, in_place, true, true).await.unwrap();
+			assert_eq!(before, snapshot(&root));
+		}
+	}
+}
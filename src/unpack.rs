@@ -1,6 +1,8 @@
-use std::{collections::HashSet, ffi::OsString, io::{BufRead, Seek}, path::{Path, PathBuf}, time::Duration};
+use std::{collections::{BTreeMap, HashSet}, ffi::OsString, io::{BufRead, Read, Seek}, path::{Path, PathBuf}, sync::Mutex, time::Duration};
 
-use crate::{config::GlobPattern, MAX_LUA_SIZE, TERMINATOR_HACK, MEM_PREALLOCATE_MAX, util};
+use futures_util::{StreamExt, TryStreamExt, stream};
+
+use crate::{comment, escape, config::{Config, GlobPattern}, header::{Compression, HeaderError, PackHeader, PACK_FORMAT_VERSION}, pack::{hash_hex, lua_cache_hash}, MAX_LUA_SIZE, TERMINATOR_HACK, MEM_PREALLOCATE_MAX, util};
 
 lazy_static! {
 	static ref LOADER_GLOB: GlobPattern = GlobPattern::new("autorun/*_gluapack_*.lua");
@@ -10,21 +12,234 @@ lazy_static! {
 	static ref GLUAPACK_DIR: PathBuf = PathBuf::from("gluapack");
 }
 
+/// The packed files found in one `lua/gluapack/<unique id>/` directory.
+#[derive(Default)]
+struct Pack {
+	sv_packed_file: Option<PathBuf>,
+	cl_chunk_files: Vec<PathBuf>,
+	sh_chunk_files: Vec<PathBuf>
+}
+impl Pack {
+	fn discover(packs: &mut BTreeMap<PathBuf, Pack>, chunk_file: PathBuf) {
+		let pack = packs.entry(chunk_file.parent().unwrap().to_path_buf()).or_default();
+		let file_name = chunk_file.file_name().as_ref().unwrap().to_string_lossy();
+		if file_name == "gluapack.sv.lua" {
+			pack.sv_packed_file = Some(chunk_file);
+		} else if file_name.ends_with(".sh.lua") {
+			pack.sh_chunk_files.push(chunk_file);
+		} else if file_name.ends_with(".cl.lua") {
+			pack.cl_chunk_files.push(chunk_file);
+		}
+	}
+}
+
+/// A file embedded in a pack.
+#[derive(serde::Serialize)]
+pub struct PackedEntry {
+	pub realm: &'static str,
+	pub path: String,
+	pub size: u32,
+	/// The networked chunk the file starts in. The serverside pack and compressed packs aren't split into chunks that files belong to.
+	pub chunk: Option<usize>,
+	/// The pack file the entry was read from, and its offset in it, for reporting problems with it.
+	#[serde(skip)]
+	location: (PathBuf, u64)
+}
+
+/// The entry files listed in a loader.
+#[derive(serde::Serialize, Default)]
+pub struct EntryFiles {
+	pub sv: Vec<String>,
+	pub cl: Vec<String>,
+	pub sh: Vec<String>
+}
+impl EntryFiles {
+	/// Parses the entry file tables that were written into a loader when it was packed.
+	pub fn parse(loader: &str) -> Option<EntryFiles> {
+		// The tables are the first three things passed to ipairs in the loader, in this order
+		let mut rest = loader;
+		let mut tables = Vec::with_capacity(3);
+		for _ in 0..3 {
+			let (table, after) = parse_lua_strings(&rest[rest.find("ipairs(")? + "ipairs(".len()..])?;
+			tables.push(table);
+			rest = after;
+		}
+
+		let sv = tables.pop()?;
+		let cl = tables.pop()?;
+		let sh = tables.pop()?;
+		Some(EntryFiles { sv, cl, sh })
+	}
+}
+
+/// Parses the pack format a loader supports, which was written into it when it was packed.
+fn parse_loader_format(loader: &str) -> Option<u32> {
+	let format = &loader[loader.find("GLUAPACK_FORMAT = ")? + "GLUAPACK_FORMAT = ".len()..];
+	format[..format.find(|char: char| !char.is_ascii_digit())?].parse().ok()
+}
+
+/// Parses a table of Lua strings, as written into loaders and manifests by the packer, returning it and whatever follows it.
+fn parse_lua_strings(table: &str) -> Option<(Vec<String>, &str)> {
+	let mut chars = table.strip_prefix('{')?.char_indices();
+	let mut entries = vec![];
+	loop {
+		match chars.next()? {
+			(i, '}') => return Some((entries, &table[i + 2..])),
+			(_, ',') => continue,
+			(_, '"') => {
+				let mut entry = String::new();
+				loop {
+					match chars.next()? {
+						(_, '"') => break,
+						(_, '\\') => entry.push(chars.next()?.1),
+						(_, char) => entry.push(char)
+					}
+				}
+				entries.push(entry);
+			},
+			_ => return None
+		}
+	}
+}
+
+/// The Lua cache hashes of a pack's networked chunks, as listed in its `manifest.lua`.
+#[derive(Default)]
+struct CacheManifest {
+	cl: Vec<String>,
+	sh: Vec<String>
+}
+impl CacheManifest {
+	/// Parses a manifest, as written by Packer::generate_cache_manifest.
+	fn parse(manifest: &str) -> Option<CacheManifest> {
+		let mut parsed = CacheManifest::default();
+		let mut rest = manifest.trim_end().strip_prefix("return{")?;
+		loop {
+			if let Some(table) = rest.strip_prefix("sh=") {
+				let (hashes, after) = parse_lua_strings(table)?;
+				parsed.sh = hashes;
+				rest = after;
+			} else if let Some(table) = rest.strip_prefix("cl=") {
+				let (hashes, after) = parse_lua_strings(table)?;
+				parsed.cl = hashes;
+				rest = after;
+			} else if let Some(after) = rest.strip_prefix(',') {
+				rest = after;
+			} else if rest == "}" {
+				break;
+			} else {
+				return None;
+			}
+		}
+
+		// The hashes are used as file names in the Lua cache
+		if parsed.cl.iter().chain(parsed.sh.iter()).all(|hash| hash.len() == 40 && hash.bytes().all(|byte| byte.is_ascii_hexdigit())) {
+			Some(parsed)
+		} else {
+			None
+		}
+	}
+
+	/// Reads a manifest from a pack, or from a client's Lua cache.
+	fn read(path: &Path) -> Option<CacheManifest> {
+		if let Some(manifest) = std::fs::read_to_string(path).ok().and_then(|manifest| CacheManifest::parse(&manifest)) {
+			return Some(manifest);
+		}
+		let manifest = read_cached_chunk(path).ok()?;
+		CacheManifest::parse(std::str::from_utf8(&manifest).ok()?)
+	}
+}
+
+/// The contents of one packed addon, as listed by `gluapack inspect`.
+#[derive(serde::Serialize)]
+pub struct InspectedPack {
+	pub unique_id: String,
+	/// The gluapack version that wrote the loader.
+	pub loader_version: Option<String>,
+	pub entry_files: EntryFiles,
+	pub files: Vec<PackedEntry>
+}
+
+impl InspectedPack {
+	/// Writes the pack as a human-readable table.
+	pub fn print_table<W: std::io::Write>(&self, w: &mut W) -> Result<(), std::io::Error> {
+		match &self.loader_version {
+			Some(version) => writeln!(w, "{} (loader from gluapack {})", self.unique_id, version)?,
+			None => writeln!(w, "{} (WARNING: no loader found)", self.unique_id)?
+		}
+		writeln!(w)?;
+
+		let chunks = self.files.iter().map(|file| file.chunk.map(|chunk| chunk.to_string()).unwrap_or_else(|| "-".to_string())).collect::<Vec<_>>();
+		let chunk_width = chunks.iter().map(|chunk| chunk.len()).max().unwrap_or(0).max("CHUNK".len());
+		let size_width = self.files.iter().map(|file| file.size.to_string().len()).max().unwrap_or(0).max("SIZE".len());
+
+		writeln!(w, "REALM  {:>chunk_width$}  {:>size_width$}  PATH", "CHUNK", "SIZE", chunk_width = chunk_width, size_width = size_width)?;
+		for (file, chunk) in self.files.iter().zip(chunks) {
+			writeln!(w, "{:<5}  {:>chunk_width$}  {:>size_width$}  {}", file.realm, chunk, file.size, file.path, chunk_width = chunk_width, size_width = size_width)?;
+		}
+		writeln!(w)?;
+
+		writeln!(w, "{} file(s), {} bytes", self.files.len(), self.files.iter().map(|file| file.size as u64).sum::<u64>())?;
+		writeln!(w)?;
+
+		writeln!(w, "Entry files:")?;
+		for (realm, entry_files) in [("sv", &self.entry_files.sv), ("cl", &self.entry_files.cl), ("sh", &self.entry_files.sh)] {
+			for entry_file in entry_files {
+				writeln!(w, "  {}  {}", realm, entry_file)?;
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// The problems found in one packed addon by `gluapack verify`.
+pub struct VerifiedPack {
+	pub unique_id: String,
+	pub problems: Vec<UnpackingError>
+}
+
+/// What the unpacker does with the files it reads from a pack.
+pub enum EntrySink {
+	/// Writes them to the output directory.
+	Unpack,
+	/// Lists them, without writing anything.
+	Inspect(Mutex<Vec<PackedEntry>>),
+	/// Writes the ones matching `pattern` to stdout, or to the output directory.
+	Cat {
+		pattern: GlobPattern,
+		to_stdout: bool,
+		matched: Mutex<usize>
+	}
+}
+
 pub struct Unpacker {
 	pub dir: PathBuf,
 	pub out_dir: PathBuf,
-	pub quiet: bool
+	pub quarantine: bool,
+	pub dry_run: bool,
+	pub quiet: bool,
+	pub sink: EntrySink,
+	/// The config the packs were made with, as far as it can be recovered from them, if it's being written to `gluapack.json`.
+	pub recovered_config: Option<Mutex<Config>>
 }
 impl Unpacker {
-	pub async fn unpack(dir: PathBuf, out_dir: Option<PathBuf>, no_copy: bool, quiet: bool) -> Result<(usize, usize, Duration), UnpackingError> {
+	#[allow(clippy::too_many_arguments)]
+	pub async fn unpack(dir: PathBuf, out_dir: Option<PathBuf>, no_copy: bool, quarantine: bool, recover_config: bool, jobs: usize, dry_run: bool, quiet: bool) -> Result<(usize, usize, Duration), UnpackingError> {
 		quietln!(quiet, "Addon Path: {}", util::canonicalize(&dir).display());
 
-		let out_dir = if let Some(out_dir) = out_dir {
-			util::prepare_output_dir(quiet, &out_dir).await;
-			out_dir
+		let (in_place, out_dir) = if let Some(out_dir) = out_dir {
+			if dry_run {
+				quietln!(quiet, "Output Path: {}", util::canonicalize(&out_dir).display());
+				if out_dir.exists() {
+					quietln!(quiet, "Would delete the old output directory");
+				}
+			} else {
+				util::prepare_output_dir(quiet, &out_dir).await?;
+			}
+			(false, out_dir)
 		} else {
 			quietln!(quiet, "Output Path: In-place");
-			dir.clone()
+			(true, dir.clone())
 		};
 
 		quietln!(quiet);
@@ -33,64 +248,503 @@ impl Unpacker {
 		let mut unpacker = Unpacker {
 			out_dir,
 			dir,
-			quiet
+			quarantine,
+			dry_run,
+			quiet,
+			sink: EntrySink::Unpack,
+			// Every file is listed in the realm it was packed in, so none of the default patterns are used
+			recovered_config: if recover_config {
+				Some(Mutex::new(Config {
+					include_sh: vec![],
+					include_cl: vec![],
+					include_sv: vec![],
+					exclude: vec![],
+					entry_cl: vec![],
+					entry_sh: vec![],
+					entry_sv: vec![],
+					..Default::default()
+				}))
+			} else {
+				None
+			}
 		};
 
 		let started = std::time::Instant::now();
 
-		let (sv_packed_file, cl_chunk_files, sh_chunk_files) = if no_copy {
+		let packs = if no_copy || in_place {
 			quietln!(quiet, "Discovering chunk files...");
 
-			let (mut cl_chunk_files, mut sh_chunk_files) = (vec![], vec![]);
-
-			for entry in util::glob(unpacker.dir.join("lua/gluapack/*/*.lua").to_string_lossy()).unwrap().filter_map(|result| result.ok()) {
-				let file_name = entry.file_name().as_ref().unwrap().to_string_lossy();
-				if file_name.ends_with(".sh.lua") {
-					sh_chunk_files.push(entry.clone());
-				} else if file_name.ends_with(".cl.lua") {
-					cl_chunk_files.push(entry.clone());
-				}
+			let mut packs = BTreeMap::new();
+			for chunk_file in util::glob(unpacker.dir.join("lua/gluapack/*/*.lua").to_string_lossy()).unwrap().filter_map(|result| result.ok()) {
+				Pack::discover(&mut packs, chunk_file);
 			}
-
-			(
-				util::glob(unpacker.dir.join("lua/gluapack/autorun/*_gluapack_*.lua").to_string_lossy()).unwrap().find_map(|result| result.ok()),
-				cl_chunk_files,
-				sh_chunk_files
-			)
+			packs
 		} else {
 			quietln!(quiet, "Copying addon to output directory...");
-			let dir = unpacker.dir.clone();
-			let out_dir = unpacker.out_dir.clone();
-			tokio::task::spawn_blocking(move || Unpacker::copy_addon(dir, out_dir)).await.expect("Failed to join thread")?
+			Unpacker::copy_addon(unpacker.dir.clone(), unpacker.out_dir.clone(), jobs, dry_run, quiet).await?
 		};
 
 		unpacker.out_dir.push("lua");
 		unpacker.dir.push("lua");
 
-		let mut total_packed_files = cl_chunk_files.len() + sh_chunk_files.len();
+		let mut total_packed_files = 0;
 		let mut total_unpacked_files = 0;
 
-		if let Some(sv_packed_file) = sv_packed_file {
-			total_packed_files += 1;
+		let total_packs = packs.len();
+		for (pack_dir, mut pack) in packs {
+			// Chunks must be read in order, and neither glob nor read_dir sort gluapack.10.cl.lua after gluapack.9.cl.lua
+			pack.cl_chunk_files.sort_by_key(|path| chunk_index(path));
+			pack.sh_chunk_files.sort_by_key(|path| chunk_index(path));
+			check_chunk_sequence(&pack_dir, &pack.cl_chunk_files, "cl")?;
+			check_chunk_sequence(&pack_dir, &pack.sh_chunk_files, "sh")?;
+
+			// + manifest.lua and the loader
+			total_packed_files += pack.cl_chunk_files.len() + pack.sh_chunk_files.len() + 2;
+
+			if let Some(sv_packed_file) = pack.sv_packed_file {
+				total_packed_files += 1;
+
+				quietln!(unpacker.quiet, "Unpacking serverside files...");
+				// Parse the serverside pack file and unpack it!
+				total_unpacked_files += unpacker.parse_sv_packed_file(sv_packed_file).await?;
+			}
+
+			quietln!(unpacker.quiet, "Unpacking clientside files...");
+			total_unpacked_files += unpacker.parse_packed_files(pack.cl_chunk_files, "cl", read_chunk_file).await?;
+
+			quietln!(unpacker.quiet, "Unpacking shared files...");
+			total_unpacked_files += unpacker.parse_packed_files(pack.sh_chunk_files, "sh", read_chunk_file).await?;
 
-			quietln!(quiet, "Unpacking serverside files...");
-			// Parse the serverside pack file and unpack it!
-			total_unpacked_files += unpacker.parse_sv_packed_file(sv_packed_file).await?;
+			unpacker.recover_loader(&pack_dir)?;
 		}
 
-		quietln!(quiet, "Unpacking clientside files...");
-		total_unpacked_files += unpacker.parse_packed_files(cl_chunk_files).await?;
+		if let Some(config) = unpacker.recovered_config.take() {
+			unpacker.write_recovered_config(config.into_inner().unwrap(), total_packs)?;
+		}
 
-		quietln!(quiet, "Unpacking shared files...");
-		total_unpacked_files += unpacker.parse_packed_files(sh_chunk_files).await?;
+		if in_place {
+			quietln!(quiet, "Deleting gluapack files...");
+			unpacker.delete_gluapack_files(jobs).await?;
+		}
 
-		Ok((total_unpacked_files, total_packed_files + 2, started.elapsed()))
+		Ok((total_unpacked_files, total_packed_files, started.elapsed()))
 	}
 
-	fn copy_addon(dir: PathBuf, out_dir: PathBuf) -> Result<(Option<PathBuf>, Vec<PathBuf>, Vec<PathBuf>), std::io::Error> {
-		std::fs::create_dir_all(&out_dir)?;
+	/// Unpacks the clientside and shared files of the packs in a client's Lua cache (`garrysmod/cache/lua`), which is exactly the code that client ran.
+	///
+	/// A pack's chunks are found in the cache using its manifest. If one isn't given, every manifest in the cache is used, as clients cache those too.
+	pub async fn unpack_cache(cache_dir: PathBuf, manifest: Option<PathBuf>, out_dir: PathBuf, quarantine: bool, dry_run: bool, quiet: bool) -> Result<(usize, usize, Duration), UnpackingError> {
+		quietln!(quiet, "Lua Cache Path: {}", util::canonicalize(&cache_dir).display());
+
+		let started = std::time::Instant::now();
+
+		let explicit_manifest = manifest.is_some();
+		let manifests = match manifest {
+			Some(manifest) => match CacheManifest::read(&manifest) {
+				Some(parsed) => vec![(manifest, parsed)],
+				None => return Err(error!(UnpackingError::MalformedManifest(manifest)))
+			},
+			None => {
+				let mut manifests = vec![];
+				for cached in util::glob(cache_dir.join("*.lua").to_string_lossy()).unwrap().filter_map(|result| result.ok()) {
+					let manifest = read_cached_chunk(&cached).ok().and_then(|manifest| CacheManifest::parse(std::str::from_utf8(&manifest).ok()?));
+					match manifest {
+						Some(manifest) if !manifest.cl.is_empty() || !manifest.sh.is_empty() => manifests.push((cached, manifest)),
+						_ => {}
+					}
+				}
+				if manifests.is_empty() {
+					return Err(error!(UnpackingError::NoCachedManifests(cache_dir)));
+				}
+				manifests
+			}
+		};
+
+		if dry_run {
+			quietln!(quiet, "Output Path: {}", util::canonicalize(&out_dir).display());
+			if out_dir.exists() {
+				quietln!(quiet, "Would delete the old output directory");
+			}
+		} else {
+			util::prepare_output_dir(quiet, &out_dir).await?;
+		}
+
+		quietln!(quiet);
+
+		let unpacker = Unpacker {
+			out_dir: out_dir.join("lua"),
+			dir: cache_dir,
+			quarantine,
+			dry_run,
+			quiet,
+			sink: EntrySink::Unpack,
+			recovered_config: None
+		};
+
+		let mut total_cache_files = 0;
+		let mut total_unpacked_files = 0;
+
+		for (manifest_path, manifest) in manifests {
+			let cached_chunks = |hashes: &[String]| hashes.iter().map(|hash| unpacker.dir.join(format!("{}.lua", hash))).collect::<Vec<_>>();
+			let cl_chunk_files = cached_chunks(&manifest.cl);
+			let sh_chunk_files = cached_chunks(&manifest.sh);
+
+			// Make sure the whole pack is there before unpacking any of it
+			let missing = cl_chunk_files.iter().enumerate().map(|(i, chunk_file)| ("cl", i + 1, chunk_file))
+				.chain(sh_chunk_files.iter().enumerate().map(|(i, chunk_file)| ("sh", i + 1, chunk_file)))
+				.find(|(_, _, chunk_file)| !chunk_file.is_file());
+			if let Some((realm, index, chunk_file)) = missing {
+				let error = error!(UnpackingError::MissingCachedChunk { file: chunk_file.clone(), realm, index });
+				if explicit_manifest {
+					return Err(error);
+				}
+				// Old manifests are left in the cache when a pack changes, but their chunks may not be
+				eprintln!("WARNING: Skipped the pack listed in {}: {}", manifest_path.display(), error);
+				continue;
+			}
+
+			quietln!(quiet, "Unpacking the pack listed in {}...", manifest_path.display());
+
+			// + the manifest
+			total_cache_files += cl_chunk_files.len() + sh_chunk_files.len() + 1;
+
+			total_unpacked_files += unpacker.parse_packed_files(cl_chunk_files, "cl", read_cached_chunk).await?;
+			total_unpacked_files += unpacker.parse_packed_files(sh_chunk_files, "sh", read_cached_chunk).await?;
+		}
+
+		Ok((total_unpacked_files, total_cache_files, started.elapsed()))
+	}
+
+	/// Lists the files in every pack in an addon, without unpacking them.
+	pub async fn inspect(dir: PathBuf) -> Result<Vec<InspectedPack>, UnpackingError> {
+		let unpacker = Unpacker {
+			out_dir: dir.join("lua"),
+			dir,
+			quarantine: false,
+			dry_run: false,
+			quiet: true,
+			sink: EntrySink::Inspect(Mutex::new(vec![])),
+			recovered_config: None
+		};
+
+		let mut inspected = vec![];
+		for (pack_dir, pack) in unpacker.discover_packs() {
+			unpacker.parse_pack(&pack_dir, pack).await?;
+
+			let unique_id = pack_dir.file_name().unwrap().to_string_lossy().into_owned();
+			let (loader_version, entry_files) = match Unpacker::find_loader(&unpacker.out_dir, &unique_id) {
+				Some((version, loader)) => (Some(version), EntryFiles::parse(&std::fs::read_to_string(loader)?).unwrap_or_default()),
+				None => (None, EntryFiles::default())
+			};
+
+			let files = match &unpacker.sink {
+				EntrySink::Inspect(inspected) => std::mem::take(&mut *inspected.lock().unwrap()),
+				_ => unreachable!()
+			};
+
+			inspected.push(InspectedPack {
+				unique_id,
+				loader_version,
+				entry_files,
+				files
+			});
+		}
+
+		Ok(inspected)
+	}
+
+	/// Writes the packed files whose paths match `pattern` to stdout, or to `out_dir` if given, without unpacking anything else.
+	///
+	/// Returns how many files matched.
+	pub async fn cat(dir: PathBuf, pattern: GlobPattern, out_dir: Option<PathBuf>, quiet: bool) -> Result<usize, UnpackingError> {
+		let unpacker = Unpacker {
+			out_dir: out_dir.clone().unwrap_or_else(|| dir.join("lua")),
+			dir,
+			quarantine: false,
+			dry_run: false,
+			quiet,
+			sink: EntrySink::Cat {
+				pattern,
+				to_stdout: out_dir.is_none(),
+				matched: Mutex::new(0)
+			},
+			recovered_config: None
+		};
+
+		for (pack_dir, pack) in unpacker.discover_packs() {
+			unpacker.parse_pack(&pack_dir, pack).await?;
+		}
+
+		match &unpacker.sink {
+			EntrySink::Cat { matched, .. } => Ok(*matched.lock().unwrap()),
+			_ => unreachable!()
+		}
+	}
+
+	/// Checks every pack in an addon for anything that would stop it from loading, without unpacking it.
+	///
+	/// Problems with the packs are collected rather than returned as errors, so that they can all be reported at once.
+	pub async fn verify(dir: PathBuf) -> Result<Vec<VerifiedPack>, UnpackingError> {
+		let unpacker = Unpacker {
+			out_dir: dir.join("lua"),
+			dir,
+			quarantine: false,
+			dry_run: false,
+			quiet: true,
+			sink: EntrySink::Inspect(Mutex::new(vec![])),
+			recovered_config: None
+		};
 
-		fn copy_addon(visited_symlinks: &mut HashSet<PathBuf>, lua_folder: &Path, from: PathBuf, to: PathBuf, sv_packed_file: &mut Option<PathBuf>, cl_chunk_files: &mut Vec<PathBuf>, sh_chunk_files: &mut Vec<PathBuf>) -> Result<(), std::io::Error> {
+		let mut verified = vec![];
+		for (pack_dir, mut pack) in unpacker.discover_packs() {
+			let unique_id = pack_dir.file_name().unwrap().to_string_lossy().into_owned();
+			let mut problems = vec![];
+
+			pack.cl_chunk_files.sort_by_key(|path| chunk_index(path));
+			pack.sh_chunk_files.sort_by_key(|path| chunk_index(path));
+
+			// Clients look the networked chunks up in their Lua cache using the hashes in the manifest
+			let manifest_path = pack_dir.join("manifest.lua");
+			match std::fs::read_to_string(&manifest_path) {
+				Ok(manifest) => match CacheManifest::parse(&manifest) {
+					Some(manifest) => {
+						problems.extend(verify_cache_hashes(&pack_dir, &pack.cl_chunk_files, &manifest.cl, "cl")?);
+						problems.extend(verify_cache_hashes(&pack_dir, &pack.sh_chunk_files, &manifest.sh, "sh")?);
+					},
+					None => problems.push(error!(UnpackingError::MalformedManifest(manifest_path)))
+				},
+				Err(error) if error.kind() == std::io::ErrorKind::NotFound => problems.push(error!(UnpackingError::MissingManifest(manifest_path))),
+				Err(error) => return Err(error.into())
+			}
+
+			// Reading the whole pack checks for missing chunks and anything corrupt in them
+			let contents_ok = match unpacker.parse_pack(&pack_dir, pack).await {
+				Ok(_) => true,
+				Err(error) => {
+					// The chunk may already have been found missing using the manifest
+					let reported = match &error {
+						UnpackingError::MissingChunk { file, .. } => problems.iter().any(|problem| matches!(problem, UnpackingError::MissingChunk { file: reported, .. } if reported == file)),
+						_ => false
+					};
+					if !reported {
+						problems.push(error);
+					}
+					false
+				}
+			};
+
+			let files = match &unpacker.sink {
+				EntrySink::Inspect(inspected) => std::mem::take(&mut *inspected.lock().unwrap()),
+				_ => unreachable!()
+			};
+
+			// Unpacking would refuse these files
+			for entry in files.iter().filter(|entry| util::escapes_lua_folder(&entry.path)) {
+				let (file, offset) = entry.location.clone();
+				problems.push(error!(UnpackingError::PathTraversal { file, offset, path: entry.path.clone() }));
+			}
+
+			match Unpacker::find_loader(&unpacker.out_dir, &unique_id) {
+				Some((_, loader_path)) => {
+					let loader = std::fs::read_to_string(&loader_path)?;
+
+					let format = parse_loader_format(&loader);
+					if format != Some(PACK_FORMAT_VERSION) {
+						problems.push(error!(UnpackingError::LoaderFormatMismatch { file: loader_path.clone(), format }));
+					}
+
+					match EntryFiles::parse(&loader) {
+						// If the pack couldn't be read, every entry file would look like it's missing
+						Some(entry_files) => if contents_ok {
+							// Shared files are unpacked on both the server and the client, so serverside and clientside entry files can be shared files too
+							for (realm, entry_files, realms) in [("sh", &entry_files.sh, &["sh"][..]), ("cl", &entry_files.cl, &["cl", "sh"][..]), ("sv", &entry_files.sv, &["sv", "sh"][..])] {
+								for entry_file in entry_files {
+									if !files.iter().any(|file| file.path == *entry_file && realms.contains(&file.realm)) {
+										problems.push(error!(UnpackingError::MissingEntryFile { file: loader_path.clone(), realm, path: entry_file.clone() }));
+									}
+								}
+							}
+						},
+						None => problems.push(error!(UnpackingError::MalformedLoader(loader_path)))
+					}
+				},
+				None => problems.push(error!(UnpackingError::MissingLoader(pack_dir.clone())))
+			}
+
+			verified.push(VerifiedPack { unique_id, problems });
+		}
+
+		Ok(verified)
+	}
+
+	/// Adds an unpacked file to the recovered config, in the realm it was packed in.
+	fn recover_file(&self, realm: &str, path: &str) {
+		if let Some(config) = &self.recovered_config {
+			let mut config = config.lock().unwrap();
+			let include = match realm {
+				"sv" => &mut config.include_sv,
+				"cl" => &mut config.include_cl,
+				_ => &mut config.include_sh
+			};
+			include.push(glob::Pattern::new(&glob::Pattern::escape(path)).unwrap().into());
+		}
+	}
+
+	/// Records whether a realm's pack was compressed in the recovered config.
+	fn recover_compression(&self, realm: &str, compression: Compression) {
+		if let Some(config) = &self.recovered_config {
+			let mut config = config.lock().unwrap();
+			let compressed = compression == Compression::Lzma;
+			match realm {
+				"sv" => config.compress_sv = compressed,
+				_ => config.compress_networked |= compressed
+			}
+		}
+	}
+
+	/// Records a pack's unique ID and the entry files its loader runs in the recovered config.
+	fn recover_loader(&self, pack_dir: &Path) -> Result<(), UnpackingError> {
+		let config = match &self.recovered_config {
+			Some(config) => config,
+			None => return Ok(())
+		};
+
+		let unique_id = pack_dir.file_name().unwrap().to_string_lossy().into_owned();
+		let entry_files = match Unpacker::find_loader(&self.dir, &unique_id) {
+			Some((_, loader)) => EntryFiles::parse(&std::fs::read_to_string(&loader)?),
+			None => None
+		};
+
+		let mut config = config.lock().unwrap();
+		config.unique_id = Some(unique_id);
+
+		match entry_files {
+			Some(entry_files) => {
+				let patterns = |entry_files: Vec<String>| entry_files.iter().map(|entry_file| glob::Pattern::new(&glob::Pattern::escape(entry_file)).unwrap().into()).collect();
+				config.entry_sv = patterns(entry_files.sv);
+				config.entry_cl = patterns(entry_files.cl);
+				config.entry_sh = patterns(entry_files.sh);
+			},
+			None => eprintln!("WARNING: Couldn't read the entry files from the loader for {}, so the recovered gluapack.json won't have any", self.display_path(pack_dir))
+		}
+
+		Ok(())
+	}
+
+	/// Writes the recovered config to the root of the output directory.
+	///
+	/// A config can only describe one pack, and a config that's already there (when unpacking in-place) is never overwritten.
+	fn write_recovered_config(&self, config: Config, total_packs: usize) -> Result<(), UnpackingError> {
+		let out_dir = self.out_dir.parent().unwrap();
+
+		if total_packs != 1 {
+			eprintln!("WARNING: Couldn't recover gluapack.json, because this addon contains {} packed addons rather than one", total_packs);
+			return Ok(());
+		}
+
+		if let Some(existing) = Config::FILE_NAMES.iter().find(|file_name| out_dir.join(file_name).is_file()) {
+			eprintln!("WARNING: Didn't recover gluapack.json, because the addon already has a {}", existing);
+			return Ok(());
+		}
+
+		let path = out_dir.join("gluapack.json");
+		let config = serde_json::to_string_pretty(&config).expect("Failed to serialize config");
+		if self.dry_run {
+			quietln!(self.quiet, "Would write {} ({} bytes)", self.display_path(&path), config.len());
+		} else {
+			quietln!(self.quiet, "Writing recovered gluapack.json...");
+			std::fs::write(path, config)?;
+		}
+
+		Ok(())
+	}
+
+	/// Finds the packs in the addon, without copying anything.
+	fn discover_packs(&self) -> BTreeMap<PathBuf, Pack> {
+		let mut packs = BTreeMap::new();
+		for chunk_file in util::glob(self.dir.join("lua/gluapack/*/*.lua").to_string_lossy()).unwrap().filter_map(|result| result.ok()) {
+			Pack::discover(&mut packs, chunk_file);
+		}
+		packs
+	}
+
+	/// Reads every file in a pack into the sink.
+	async fn parse_pack(&self, pack_dir: &Path, mut pack: Pack) -> Result<(), UnpackingError> {
+		pack.cl_chunk_files.sort_by_key(|path| chunk_index(path));
+		pack.sh_chunk_files.sort_by_key(|path| chunk_index(path));
+		check_chunk_sequence(pack_dir, &pack.cl_chunk_files, "cl")?;
+		check_chunk_sequence(pack_dir, &pack.sh_chunk_files, "sh")?;
+
+		if let Some(sv_packed_file) = pack.sv_packed_file {
+			self.parse_sv_packed_file(sv_packed_file).await?;
+		}
+		self.parse_packed_files(pack.cl_chunk_files, "cl", read_chunk_file).await?;
+		self.parse_packed_files(pack.sh_chunk_files, "sh", read_chunk_file).await?;
+
+		Ok(())
+	}
+
+	/// Finds the loader for a pack, returning the gluapack version that wrote it along with its path.
+	fn find_loader(lua_dir: &Path, unique_id: &str) -> Option<(String, PathBuf)> {
+		util::glob(lua_dir.join(format!("autorun/{}_gluapack_*.lua", glob::Pattern::escape(unique_id))).to_string_lossy()).unwrap()
+			.filter_map(|result| result.ok())
+			.find_map(|loader| {
+				let version = loader.file_name()?.to_str()?.strip_prefix(unique_id)?.strip_prefix("_gluapack_")?.strip_suffix(".lua")?.to_owned();
+				Some((version, loader))
+			})
+	}
+
+	/// Deletes the loader and packed files from an addon that was unpacked in-place, `jobs` at a time.
+	async fn delete_gluapack_files(&self, jobs: usize) -> Result<(), UnpackingError> {
+		let loaders = util::glob(self.out_dir.join("autorun/*_gluapack_*.lua").to_string_lossy()).unwrap().filter_map(|result| result.ok()).collect::<Vec<_>>();
+		let gluapack_dirs = util::glob(self.out_dir.join("gluapack/*").to_string_lossy()).unwrap().filter_map(|result| result.ok()).filter(|path| path.is_dir()).collect::<Vec<_>>();
+
+		if self.dry_run {
+			for loader in loaders {
+				quietln!(self.quiet, "Would delete {}", self.display_path(&loader));
+			}
+			for gluapack_dir in gluapack_dirs {
+				quietln!(self.quiet, "Would delete {}/", self.display_path(&gluapack_dir));
+			}
+			return Ok(());
+		}
+
+		stream::iter(loaders.into_iter().map(|loader| (loader, false)).chain(gluapack_dirs.into_iter().map(|gluapack_dir| (gluapack_dir, true))))
+			.map(|(path, is_dir)| async move {
+				if is_dir {
+					tokio::fs::remove_dir_all(path).await
+				} else {
+					tokio::fs::remove_file(path).await
+				}
+			})
+			.buffer_unordered(jobs)
+			.try_for_each(|_| async { Ok(()) })
+			.await?;
+
+		tokio::fs::remove_dir(self.out_dir.join("gluapack")).await.ok();
+		Ok(())
+	}
+
+	/// Formats the path of an output file relative to the output directory, for printing.
+	fn display_path(&self, path: &Path) -> String {
+		path.strip_prefix(self.out_dir.parent().unwrap()).unwrap_or(path).to_string_lossy().replace('\\', "/")
+	}
+
+	/// Copies everything but the gluapack files to the output directory, `jobs` files at a time, returning the packs found. In a dry run, this just reports what would be copied.
+	async fn copy_addon(dir: PathBuf, out_dir: PathBuf, jobs: usize, dry_run: bool, quiet: bool) -> Result<BTreeMap<PathBuf, Pack>, std::io::Error> {
+		if !dry_run {
+			tokio::fs::create_dir_all(&out_dir).await?;
+		}
+
+		struct Copy<'a> {
+			lua_folder: &'a Path,
+			out_dir: &'a Path,
+			dry_run: bool,
+			quiet: bool
+		}
+
+		/// Creates the output directories as it goes, and collects the files to copy into `files`.
+		fn copy_addon(visited_symlinks: &mut HashSet<PathBuf>, copy: &Copy, from: PathBuf, to: PathBuf, packs: &mut BTreeMap<PathBuf, Pack>, files: &mut Vec<(PathBuf, PathBuf)>) -> Result<(), std::io::Error> {
 			#[cfg(target_os = "windows")]
 			const FILE_ATTRIBUTE_HIDDEN: u32 = 0x02;
 
@@ -112,23 +766,16 @@ impl Unpacker {
 				let file_name = entry.file_name().as_ref().unwrap().to_string_lossy();
 
 				// If we're in <dir>/lua
-				let skip_copy = if let Ok(lua_relative) = entry.strip_prefix(lua_folder) {
+				let skip_copy = if let Ok(lua_relative) = entry.strip_prefix(copy.lua_folder) {
 					// Skip gluapack files
 					if entry.is_dir() {
-						lua_relative == &*GLUAPACK_DIR || CHUNK_DIR_GLOB.matches_path(lua_relative)
+						lua_relative == *GLUAPACK_DIR || CHUNK_DIR_GLOB.matches_path(lua_relative)
 					} else {
 						if LOADER_GLOB.matches_path(lua_relative) {
 							continue;
 						} else if CHUNK_FILE_GLOB.matches_path(lua_relative) {
 							// Remember chunk files for later
-							if &file_name == "gluapack.sv.lua" {
-								debug_assert!(sv_packed_file.is_none());
-								*sv_packed_file = Some(entry.clone());
-							} else if file_name.ends_with(".sh.lua") {
-								sh_chunk_files.push(entry.clone());
-							} else if file_name.ends_with(".cl.lua") {
-								cl_chunk_files.push(entry.clone());
-							}
+							Pack::discover(packs, entry);
 							continue;
 						} else {
 							false
@@ -138,8 +785,8 @@ impl Unpacker {
 					false
 				};
 
-				if file_name.starts_with(".") || file_name == "gluapack.json" {
-					// Skip hidden files/dirs and gluapack.json
+				if file_name.starts_with(".") || Config::FILE_NAMES.contains(&file_name.as_ref()) {
+					// Skip hidden files/dirs and gluapack config files
 					continue;
 				}
 
@@ -153,139 +800,443 @@ impl Unpacker {
 
 				if entry.is_dir() {
 					let dir = to.join(&file_name);
-					if !skip_copy {
+					if !skip_copy && !copy.dry_run {
 						std::fs::create_dir_all(&dir)?;
 					}
-					copy_addon(visited_symlinks, lua_folder, entry, dir, sv_packed_file, cl_chunk_files, sh_chunk_files)?;
+					copy_addon(visited_symlinks, copy, entry, dir, packs, files)?;
 				} else if entry.is_file() && !skip_copy {
-					std::fs::copy(entry, to.join(&file_name))?;
+					let to = to.join(&file_name);
+					if copy.dry_run {
+						quietln!(copy.quiet, "Would copy {}", to.strip_prefix(copy.out_dir).unwrap_or(&to).to_string_lossy().replace('\\', "/"));
+					} else {
+						files.push((entry, to));
+					}
 				}
 			}
 			Ok(())
 		}
 
-		let mut sv_packed_file = None;
-		let mut cl_chunk_files = vec![];
-		let mut sh_chunk_files = vec![];
+		let (packs, files) = tokio::task::spawn_blocking(move || {
+			let mut packs = BTreeMap::new();
+			let mut files = vec![];
+
+			let mut visited_symlinks = HashSet::new();
+			let copy = Copy {
+				lua_folder: &dir.join("lua"),
+				out_dir: &out_dir,
+				dry_run,
+				quiet
+			};
+			copy_addon(&mut visited_symlinks, &copy, dir.clone(), out_dir.clone(), &mut packs, &mut files)?;
+
+			Ok::<_, std::io::Error>((packs, files))
+		}).await.expect("Failed to join thread")?;
+
+		stream::iter(files)
+			.map(|(from, to)| tokio::fs::copy(from, to))
+			.buffer_unordered(jobs)
+			.try_for_each(|_| async { Ok(()) })
+			.await?;
+
+		Ok(packs)
+	}
+
+	/// Writes the contents of an entry to its path in the output directory.
+	///
+	/// Entries whose path would escape the `lua/` folder are rejected, or skipped if we're quarantining them.
+	fn unpack_entry<R: Read + Seek>(&self, f: &mut R, chunks: &ChunkMap, offset: u64, path: &str, len: u32) -> Result<(), UnpackingError> {
+		let mut out: Box<dyn std::io::Write> = match (&self.sink, entry_out_path(&self.out_dir, path)) {
+			(EntrySink::Inspect(inspected), _) => {
+				inspected.lock().unwrap().push(PackedEntry {
+					realm: chunks.realm,
+					path: path.to_owned(),
+					size: len,
+					chunk: chunks.chunk_index(offset),
+					location: chunks.locate(offset)
+				});
+				Box::new(std::io::sink())
+			},
+
+			(EntrySink::Cat { pattern, .. }, _) if !pattern.matches(path) => Box::new(std::io::sink()),
+
+			(EntrySink::Cat { to_stdout, matched, .. }, out_path) => {
+				*matched.lock().unwrap() += 1;
+				match out_path {
+					_ if *to_stdout => Box::new(std::io::stdout()),
+					Some(out_path) => {
+						if let Some(parent) = out_path.parent() {
+							std::fs::create_dir_all(parent)?;
+						}
+						quietln!(self.quiet, "{}", path);
+						Box::new(std::fs::File::create(out_path)?)
+					},
+					None => {
+						eprintln!("WARNING: Skipped {:?} because its path escapes the output directory", path);
+						Box::new(std::io::sink())
+					}
+				}
+			},
+
+			(EntrySink::Unpack, Some(out_path)) if self.dry_run => {
+				self.recover_file(chunks.realm, path);
+				quietln!(self.quiet, "Would write {} ({} bytes)", self.display_path(&out_path), len);
+				Box::new(std::io::sink())
+			},
+
+			(EntrySink::Unpack, Some(out_path)) => {
+				self.recover_file(chunks.realm, path);
+				if let Some(parent) = out_path.parent() {
+					std::fs::create_dir_all(parent)?;
+				}
+				Box::new(std::fs::File::create(out_path)?)
+			},
 
-		let mut visited_symlinks = HashSet::new();
-		copy_addon(&mut visited_symlinks, &dir.join("lua"), dir, out_dir, &mut sv_packed_file, &mut cl_chunk_files, &mut sh_chunk_files)?;
+			(EntrySink::Unpack, None) => {
+				let (file, offset) = chunks.locate(offset);
+				if !self.quarantine {
+					return Err(error!(UnpackingError::PathTraversal { file, offset, path: path.to_owned() }));
+				}
+				eprintln!("WARNING: Quarantined {:?} (byte {} of {}) because its path escapes the lua/ folder", path, offset, file.display());
+				Box::new(std::io::sink())
+			}
+		};
 
-		Ok((sv_packed_file, cl_chunk_files, sh_chunk_files))
+		if std::io::copy(&mut f.take(len as u64), &mut out)? < len as u64 {
+			return Err(chunks.truncated_entry(f.stream_position()?, path.as_bytes()));
+		}
+
+		Ok(())
 	}
 
 	async fn parse_sv_packed_file(&self, sv_packed_file: PathBuf) -> Result<usize, UnpackingError> {
-		use std::{fs::File, io::{BufReader, Read}};
+		use std::{fs::File, io::{BufReader, Cursor}};
+
+		let mut length = sv_packed_file.metadata()?.len();
+
+		let mut f = BufReader::new(File::open(&sv_packed_file)?);
+		let header = read_header(&sv_packed_file, &mut f, "sv")?;
+		self.recover_compression("sv", header.compression);
+		let mut header_len = f.stream_position()?;
 
-		let mut entries = 0;
+		let chunks = ChunkMap::new("sv", vec![(0, sv_packed_file.clone())]);
+
+		fn read_entry<R: BufRead + Seek>(unpacker: &Unpacker, f: &mut R, chunks: &ChunkMap) -> Result<String, UnpackingError> {
+			let offset = f.stream_position()?;
 
-		let mut f = BufReader::new(File::open(sv_packed_file)?);
-		fn read_entry(out_dir: &PathBuf, f: &mut BufReader<File>) -> Result<bool, std::io::Error> {
 			let mut path = Vec::with_capacity(255);
 			f.read_until(0, &mut path)?;
-
-			if path.is_empty() {
-				return Ok(true);
+			if path.pop() != Some(0) {
+				return Err(chunks.truncated_entry(f.stream_position()?, &path));
 			}
 
 			let mut len = [0u8; 4];
-			f.read_exact(&mut len)?;
+			if let Err(error) = f.read_exact(&mut len) {
+				return Err(match error.kind() {
+					std::io::ErrorKind::UnexpectedEof => chunks.truncated_entry(f.stream_position()?, &path),
+					_ => error.into()
+				});
+			}
 			let len = u32::from_le_bytes(len);
 
-			let path = out_dir.join(String::from_utf8_lossy(&path[0..path.len()-1]).as_ref());
+			let path = String::from_utf8_lossy(&path).into_owned();
+			unpacker.unpack_entry(f, chunks, offset, &path, len)?;
 
-			if let Some(parent) = path.parent() {
-				std::fs::create_dir_all(parent)?;
+			Ok(path)
+		}
+
+		fn read_entries<R: BufRead + Seek>(unpacker: &Unpacker, f: &mut R, chunks: &ChunkMap, entries: usize) -> Result<(u64, String), UnpackingError> {
+			let mut path = String::new();
+			for _ in 0..entries {
+				path = read_entry(unpacker, f, chunks)?;
 			}
+			Ok((f.stream_position()?, path))
+		}
 
-			let mut out = File::create(path)?;
-			std::io::copy(&mut f.by_ref().take(len as u64), &mut out)?;
+		let (offset, path) = if header.compression == Compression::Lzma {
+			let mut compressed = Vec::with_capacity((length - header_len).min(MEM_PREALLOCATE_MAX as u64) as usize);
+			f.read_to_end(&mut compressed)?;
+			if compressed.len() as u64 != header.length {
+				return Err(chunks.length_mismatch(length, String::new(), header.length, compressed.len() as u64));
+			}
+
+			let entries = match util::lzma_decompress(&compressed) {
+				Ok(entries) => entries,
+				Err(_) => return Err(error!(UnpackingError::BadCompression(sv_packed_file)))
+			};
+
+			// From here on, offsets are into the decompressed entries
+			length = entries.len() as u64;
+			header_len = 0;
+			read_entries(self, &mut Cursor::new(entries), &chunks, header.entries)?
+		} else {
+			read_entries(self, &mut f, &chunks, header.entries)?
+		};
 
-			Ok(false)
+		if offset != length || (header.compression == Compression::None && length - header_len != header.length) {
+			return Err(chunks.length_mismatch(offset, path, header.length, length - header_len));
 		}
-		loop {
-			match read_entry(&self.out_dir, &mut f) {
-				Ok(true) => break,
-				Ok(false) => entries += 1,
-				Err(error) => if let std::io::ErrorKind::UnexpectedEof = error.kind() {
-					break;
-				} else {
-					return Err(error!(UnpackingError::IoError(error)));
-				},
+
+		Ok(header.entries)
+	}
+
+	/// Parses a realm's networked chunks, which are read with `read_chunk` so that they can come from the addon or from a client's Lua cache.
+	async fn parse_packed_files(&self, packed_files: Vec<PathBuf>, realm: &'static str, read_chunk: fn(&Path) -> Result<Vec<u8>, UnpackingError>) -> Result<usize, UnpackingError> {
+		use std::io::Cursor;
+
+		let first_packed_file = match packed_files.first() {
+			Some(packed_file) => packed_file.clone(),
+			None => return Ok(0)
+		};
+
+		let mut chunks = ChunkMap::new(realm, Vec::with_capacity(packed_files.len()));
+		let mut superchunk = Vec::with_capacity((MAX_LUA_SIZE * packed_files.len()).min(MEM_PREALLOCATE_MAX));
+		for packed_file in packed_files {
+			let commented = read_chunk(&packed_file)?;
+			let chunk = match comment::uncommentify(&commented) {
+				Some(chunk) => chunk,
+				None => return Err(error!(UnpackingError::UncommentedChunk(packed_file)))
+			};
+			let chunk = match escape::unescape(chunk) {
+				Ok(chunk) => chunk,
+				Err(offset) => return Err(error!(UnpackingError::BadEscape { file: packed_file, offset: offset as u64 }))
+			};
+			chunks.files.push((superchunk.len() as u64, packed_file));
+			superchunk.extend_from_slice(&chunk);
+		}
+
+		let mut length = superchunk.len() as u64;
+
+		let mut f = Cursor::new(superchunk);
+		let header = read_header(&first_packed_file, &mut f, realm)?;
+		self.recover_compression(realm, header.compression);
+		let mut header_len = f.position();
+
+		if header.compression == Compression::Lzma {
+			let encoded = &f.get_ref()[header_len as usize..];
+			if encoded.len() as u64 != header.length {
+				return Err(chunks.length_mismatch(length, String::new(), header.length, encoded.len() as u64));
 			}
+
+			let entries = match base64::decode(encoded).ok().and_then(|compressed| util::lzma_decompress(&compressed).ok()) {
+				Some(entries) => entries,
+				None => return Err(error!(UnpackingError::BadCompression(first_packed_file)))
+			};
+
+			// From here on, offsets are into the decompressed entries
+			chunks = ChunkMap { realm, compressed: true, files: vec![(0, first_packed_file)] };
+			length = entries.len() as u64;
+			header_len = 0;
+			f = Cursor::new(entries);
 		}
 
-		Ok(entries)
-	}
+		fn read_entry(unpacker: &Unpacker, f: &mut Cursor<Vec<u8>>, chunks: &ChunkMap) -> Result<String, UnpackingError> {
+			let offset = f.position();
+
+			fn read_len(f: &mut Cursor<Vec<u8>>, chunks: &ChunkMap) -> Result<u32, UnpackingError> {
+				// The path hasn't been read yet
+				let path = b"";
 
-	async fn parse_packed_files(&self, packed_files: Vec<PathBuf>) -> Result<usize, UnpackingError> {
-		use std::{fs::File, io::{SeekFrom, BufReader, Read, Cursor}};
+				// Lengths are written as a hex u32, so they can't be longer than 8 digits
+				const MAX_LEN_DIGITS: u64 = 8;
 
-		let mut entries = 0;
+				let mut len = Vec::with_capacity(MAX_LEN_DIGITS as usize + 1);
+				f.by_ref().take(MAX_LEN_DIGITS + 1).read_until(TERMINATOR_HACK, &mut len)?;
+				match len.pop() {
+					Some(TERMINATOR_HACK) => {},
+					Some(_) if len.len() as u64 == MAX_LEN_DIGITS => return Err(chunks.bad_terminator(f.position(), path)),
+					_ => return Err(chunks.truncated_entry(f.position(), path))
+				}
 
-		fn read_commented_file<P: AsRef<std::path::Path>>(packed_file: P) -> Result<Vec<u8>, std::io::Error> {
-			let mut buf = Vec::with_capacity(packed_file.as_ref().metadata()?.len() as usize);
-			let mut f = BufReader::new(File::open(packed_file)?);
-			loop {
-				let mut line = String::new();
-				f.seek(SeekFrom::Current(2))?;
-				if f.read_line(&mut line)? == 0 {
-					break;
+				match std::str::from_utf8(&len).ok().and_then(|len| u32::from_str_radix(len, 16).ok()) {
+					Some(len) => Ok(len),
+					None => Err(chunks.bad_terminator(f.position(), path))
 				}
-				buf.extend_from_slice(&line.as_bytes())
 			}
-			Ok(buf)
+
+			let path_len = read_len(f, chunks)?;
+			let len = read_len(f, chunks)?;
+
+			let mut path = Vec::with_capacity(path_len as usize);
+			f.by_ref().take(path_len as u64).read_to_end(&mut path)?;
+			if path.len() != path_len as usize {
+				return Err(chunks.truncated_entry(f.position(), &path));
+			}
+
+			let path = String::from_utf8_lossy(&path).into_owned();
+			unpacker.unpack_entry(f, chunks, offset, &path, len)?;
+
+			Ok(path)
 		}
 
-		let mut superchunk = Vec::with_capacity((MAX_LUA_SIZE * packed_files.len()).min(MEM_PREALLOCATE_MAX));
-		for packed_file in packed_files {
-			superchunk.extend_from_slice(&read_commented_file(packed_file)?);
+		let mut path = String::new();
+		for _ in 0..header.entries {
+			path = read_entry(self, &mut f, &chunks)?;
 		}
 
-		fn read_entry(out_dir: &PathBuf, f: &mut std::io::Cursor<Vec<u8>>) -> Result<bool, UnpackingError> {
-			let mut path = Vec::with_capacity(255);
-			f.read_until(TERMINATOR_HACK, &mut path)?;
+		let offset = f.position();
+		if offset != length || (header.compression == Compression::None && length - header_len != header.length) {
+			return Err(chunks.length_mismatch(offset, path, header.length, length - header_len));
+		}
 
-			if path.is_empty() {
-				return Ok(true);
-			}
+		Ok(header.entries)
+	}
+}
 
-			let mut len = Vec::with_capacity(16);
-			f.read_until(TERMINATOR_HACK, &mut len)?;
+/// Joins an entry's path onto the `lua/` folder it's being unpacked into.
+///
+/// Returns `None` if the path would escape the folder (see [`util::escapes_lua_folder`]). That includes paths through a symlink that's already in the folder,
+/// such as `lua/evil -> /etc` when unpacking in-place, as writing through it could write anywhere.
+fn entry_out_path(out_dir: &Path, path: &str) -> Option<PathBuf> {
+	if util::escapes_lua_folder(path) {
+		return None;
+	}
 
-			let len = u32::from_str_radix(std::str::from_utf8(&len[0..len.len()-1])?, 16)?;
+	// Backslashes are only separators on Windows
+	#[cfg(windows)]
+	const SEPARATORS: &[char] = &['/', '\\'];
+	#[cfg(not(windows))]
+	const SEPARATORS: &[char] = &['/'];
 
-			let path = out_dir.join(String::from_utf8_lossy(&path[0..path.len()-1]).as_ref());
+	let mut out_path = out_dir.to_path_buf();
+	for component in path.split(SEPARATORS) {
+		out_path.push(component);
 
-			if let Some(parent) = path.parent() {
-				std::fs::create_dir_all(parent)?;
-			}
+		// Anything that doesn't exist yet is created as a real directory or file
+		if out_path.symlink_metadata().map(|metadata| metadata.file_type().is_symlink()).unwrap_or(false) {
+			return None;
+		}
+	}
+	Some(out_path)
+}
+
+/// Maps offsets in a realm's (uncommented) pack back to the chunk files they were read from, for error reporting.
+struct ChunkMap {
+	realm: &'static str,
+	/// Whether offsets are into decompressed entries, which don't belong to any one chunk.
+	compressed: bool,
+	files: Vec<(u64, PathBuf)>
+}
+impl ChunkMap {
+	fn new(realm: &'static str, files: Vec<(u64, PathBuf)>) -> ChunkMap {
+		ChunkMap { realm, compressed: false, files }
+	}
 
-			let mut out = File::create(path)?;
-			std::io::copy(&mut f.by_ref().take(len as u64), &mut out)?;
+	/// Returns the chunk file containing this offset, and the offset into that chunk.
+	fn locate(&self, offset: u64) -> (PathBuf, u64) {
+		let i = match self.files.binary_search_by_key(&offset, |(start, _)| *start) {
+			Ok(i) => i,
+			Err(i) => i.saturating_sub(1)
+		};
+		let (start, file) = &self.files[i];
+		(file.clone(), offset - start)
+	}
 
-			Ok(false)
+	/// Returns the index of the networked chunk containing this offset.
+	fn chunk_index(&self, offset: u64) -> Option<usize> {
+		if self.compressed || self.realm == "sv" {
+			None
+		} else {
+			chunk_index(&self.locate(offset).0)
 		}
+	}
 
-		let mut f = Cursor::new(superchunk);
-		loop {
-			match read_entry(&self.out_dir, &mut f) {
-				Ok(true) => break,
-				Ok(false) => entries += 1,
-				Err(UnpackingError::IoError { error, .. }) => if let std::io::ErrorKind::UnexpectedEof = error.kind() {
-					break;
-				} else {
-					return Err(error!(UnpackingError::IoError(error)));
-				}
-				Err(error) => return Err(error),
-			}
+	fn truncated_entry(&self, offset: u64, path: &[u8]) -> UnpackingError {
+		let (file, offset) = self.locate(offset);
+		error!(UnpackingError::TruncatedEntry { file, offset, path: String::from_utf8_lossy(path).into_owned() })
+	}
+
+	fn bad_terminator(&self, offset: u64, path: &[u8]) -> UnpackingError {
+		let (file, offset) = self.locate(offset);
+		error!(UnpackingError::BadTerminator { file, offset, path: String::from_utf8_lossy(path).into_owned() })
+	}
+
+	fn length_mismatch(&self, offset: u64, path: String, expected: u64, found: u64) -> UnpackingError {
+		let (file, offset) = self.locate(offset);
+		error!(UnpackingError::LengthMismatch { file, offset, path, expected, found })
+	}
+}
+
+/// Makes sure there are no gaps in a realm's chunk files, which must already be sorted by index.
+fn check_chunk_sequence(pack_dir: &Path, chunk_files: &[PathBuf], realm: &str) -> Result<(), UnpackingError> {
+	for (i, chunk_file) in chunk_files.iter().enumerate() {
+		if chunk_index(chunk_file) != Some(i + 1) {
+			return Err(error!(UnpackingError::MissingChunk { file: pack_dir.join(format!("gluapack.{}.{}.lua", i + 1, realm)), index: i + 1 }));
+		}
+	}
+	Ok(())
+}
+
+/// Compares the Lua cache hashes of a realm's chunk files, which must already be sorted by index, with those listed in the pack's manifest.
+fn verify_cache_hashes(pack_dir: &Path, chunk_files: &[PathBuf], hashes: &[String], realm: &str) -> Result<Vec<UnpackingError>, UnpackingError> {
+	let mut problems = vec![];
+
+	for chunk_file in chunk_files {
+		let expected = chunk_index(chunk_file).and_then(|index| hashes.get(index.checked_sub(1)?));
+		let found = hash_hex(&lua_cache_hash(&std::fs::read(chunk_file)?));
+		match expected {
+			Some(expected) if *expected == found => {},
+			Some(expected) => problems.push(error!(UnpackingError::CacheHashMismatch { file: chunk_file.clone(), expected: expected.clone(), found })),
+			None => problems.push(error!(UnpackingError::UnlistedChunk(chunk_file.clone())))
 		}
+	}
+
+	// Gaps in the chunk files are found when the pack is read, but chunks missing from the end of the pack can only be found using the manifest
+	let last_index = chunk_files.last().and_then(|chunk_file| chunk_index(chunk_file)).unwrap_or(0);
+	for index in last_index + 1..=hashes.len() {
+		problems.push(error!(UnpackingError::MissingChunk { file: pack_dir.join(format!("gluapack.{}.{}.lua", index, realm)), index }));
+	}
 
-		Ok(entries)
+	Ok(problems)
+}
+
+/// Reads a networked chunk file from an addon.
+fn read_chunk_file(path: &Path) -> Result<Vec<u8>, UnpackingError> {
+	Ok(std::fs::read(path)?)
+}
+
+/// Reads a networked chunk from a client's Lua cache, where it's stored as a 32 byte SHA-256 header followed by the LZMA compressed chunk and a NUL terminator.
+fn read_cached_chunk(path: &Path) -> Result<Vec<u8>, UnpackingError> {
+	const CACHE_HEADER_LEN: usize = 32;
+
+	let cached = std::fs::read(path)?;
+	let mut chunk = match cached.get(CACHE_HEADER_LEN..).and_then(|compressed| util::lzma_decompress(compressed).ok()) {
+		Some(chunk) => chunk,
+		None => return Err(error!(UnpackingError::BadCacheFile(path.to_path_buf())))
+	};
+	if chunk.last() == Some(&0) {
+		chunk.pop();
 	}
+	Ok(chunk)
+}
+
+/// Parses the index out of a `gluapack.<index>.<realm>.lua` chunk file name.
+fn chunk_index(path: &Path) -> Option<usize> {
+	path.file_name()?.to_str()?.split('.').nth(1)?.parse().ok()
+}
+
+/// Reads and checks the [`PackHeader`] at the start of a realm's pack.
+fn read_header<R: BufRead>(packed_file: &Path, f: &mut R, realm: &str) -> Result<PackHeader, UnpackingError> {
+	// Packs made by gluapack 0.3.0 or older have no header, so don't read the whole file looking for a newline
+	const MAX_HEADER_LEN: u64 = 256;
+
+	let mut line = Vec::with_capacity(MAX_HEADER_LEN as usize);
+	f.take(MAX_HEADER_LEN).read_until(b'\n', &mut line)?;
+
+	let header = PackHeader::parse(&line).map_err(|error| match error {
+		HeaderError::Missing => error!(UnpackingError::MissingHeader(packed_file.to_path_buf())),
+		HeaderError::UnsupportedFormat { format, version } => error!(UnpackingError::UnsupportedFormat { file: packed_file.to_path_buf(), format, version }),
+		HeaderError::Malformed(line) => error!(UnpackingError::MalformedHeader { file: packed_file.to_path_buf(), line })
+	})?;
+
+	if header.realm != realm {
+		return Err(error!(UnpackingError::RealmMismatch { file: packed_file.to_path_buf(), expected: realm.to_string(), found: header.realm }));
+	}
+
+	Ok(header)
 }
 
 #[derive(Debug, thiserror::Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum UnpackingError {
 	#[error("IO error: {error}")]
 	IoError {
@@ -307,7 +1258,459 @@ pub enum UnpackingError {
 		#[cfg(all(debug_assertions, feature = "nightly"))]
 		backtrace: std::backtrace::Backtrace
 	},
+	#[error("{} has no pack header. It was probably packed by gluapack 0.3.0 or older, from before packs had headers, which this version of gluapack can't unpack.", .error.display())]
+	MissingHeader {
+		error: PathBuf,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("{} was packed by gluapack {version} using pack format {format}, but this version of gluapack only supports pack format {}", .file.display(), PACK_FORMAT_VERSION)]
+	UnsupportedFormat {
+		file: PathBuf,
+		format: u32,
+		version: String,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("{} isn't wrapped in a Lua long comment. It was probably packed by an older version of gluapack, which this version of gluapack can't unpack.", .error.display())]
+	UncommentedChunk {
+		error: PathBuf,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("Pack is corrupt: couldn't decompress the pack starting at {}", .error.display())]
+	BadCompression {
+		error: PathBuf,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("Pack is corrupt: invalid escape sequence at byte {offset} of {}", .file.display())]
+	BadEscape {
+		file: PathBuf,
+		offset: u64,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("{} has a malformed pack header: {line:?}", .file.display())]
+	MalformedHeader {
+		file: PathBuf,
+		line: String,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("{} should contain a {expected} pack, but its header says it contains a {found} pack", .file.display())]
+	RealmMismatch {
+		file: PathBuf,
+		expected: String,
+		found: String,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("Pack is truncated: {} ended at byte {offset} while reading {path:?}", .file.display())]
+	TruncatedEntry {
+		file: PathBuf,
+		offset: u64,
+		path: String,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("Pack is corrupt: expected a terminator at byte {offset} of {} while reading the length of {path:?}", .file.display())]
+	BadTerminator {
+		file: PathBuf,
+		offset: u64,
+		path: String,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("Pack is corrupt: its header says it is {expected} bytes long, but it is {found} bytes long (stopped at byte {offset} of {} after reading {path:?})", .file.display())]
+	LengthMismatch {
+		file: PathBuf,
+		offset: u64,
+		path: String,
+		expected: u64,
+		found: u64,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("Pack contains a file whose path escapes the lua/ folder: {path:?} (byte {offset} of {}). Use --quarantine to skip these files instead.", .file.display())]
+	PathTraversal {
+		file: PathBuf,
+		offset: u64,
+		path: String,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("Pack is incomplete: chunk {index} is missing (expected {})", .file.display())]
+	MissingChunk {
+		file: PathBuf,
+		index: usize,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("Pack is incomplete: {} is missing, so clients won't be able to find the networked chunks in their Lua cache", .error.display())]
+	MissingManifest {
+		error: PathBuf,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("{} is malformed", .error.display())]
+	MalformedManifest {
+		error: PathBuf,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("{} has changed since it was packed: its Lua cache hash is {found}, but manifest.lua says {expected}", .file.display())]
+	CacheHashMismatch {
+		file: PathBuf,
+		expected: String,
+		found: String,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("{} isn't listed in manifest.lua, so clients won't be able to find it in their Lua cache", .error.display())]
+	UnlistedChunk {
+		error: PathBuf,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("Pack is incomplete: there is no loader for {} in lua/autorun/", .error.display())]
+	MissingLoader {
+		error: PathBuf,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("{} isn't a Lua cache file, or is corrupt", .error.display())]
+	BadCacheFile {
+		error: PathBuf,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("Chunk {index} of the {realm} pack isn't in the Lua cache (expected {})", .file.display())]
+	MissingCachedChunk {
+		file: PathBuf,
+		realm: &'static str,
+		index: usize,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("Couldn't find any gluapack manifests in {}. Use --manifest to give the manifest.lua of the pack you're looking for.", .error.display())]
+	NoCachedManifests {
+		error: PathBuf,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("{} has malformed entry file tables", .error.display())]
+	MalformedLoader {
+		error: PathBuf,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("{} supports pack format {}, but this version of gluapack reads and writes pack format {}", .file.display(), .format.map(|format| format.to_string()).unwrap_or_else(|| "?".to_string()), PACK_FORMAT_VERSION)]
+	LoaderFormatMismatch {
+		file: PathBuf,
+		format: Option<u32>,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
+
+	#[error("{} runs {realm} entry file {path:?}, but it isn't in the pack", .file.display())]
+	MissingEntryFile {
+		file: PathBuf,
+		realm: &'static str,
+		path: String,
+		#[cfg(all(debug_assertions, feature = "nightly"))]
+		backtrace: std::backtrace::Backtrace
+	},
 }
 impl_error!(std::io::Error, UnpackingError::IoError);
 impl_error!(std::str::Utf8Error, UnpackingError::Utf8Error);
 impl_error!(std::num::ParseIntError, UnpackingError::ParseIntError);
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn entry_out_path_stays_in_lua_folder() {
+		let out_dir = util::TempDir::new("entry-out-path");
+		std::fs::create_dir_all(out_dir.join("autorun")).unwrap();
+
+		assert_eq!(entry_out_path(&out_dir, "autorun/sh_init.lua"), Some(out_dir.join("autorun/sh_init.lua")));
+		assert_eq!(entry_out_path(&out_dir, "new/dir/cl_init.lua"), Some(out_dir.join("new/dir/cl_init.lua")));
+
+		for path in [
+			"",
+			"..",
+			"../evil.lua",
+			"autorun/../../evil.lua",
+			"./autorun/sh_init.lua",
+			"autorun//sh_init.lua",
+			"autorun/",
+			"/etc/passwd",
+			"C:/Windows/evil.lua",
+			"C:evil.lua",
+			"\\\\server\\share\\evil.lua",
+			"autorun\\..\\..\\evil.lua",
+			"evil\0.lua",
+			"autorun/C:evil.lua"
+		] {
+			assert_eq!(entry_out_path(&out_dir, path), None, "{:?}", path);
+		}
+
+		// Characters that Windows can't use in file names can't escape the folder either
+		for path in ["autorun/sh_a:b.lua", "autorun/sh_<a>|b?.lua"] {
+			assert_eq!(entry_out_path(&out_dir, path), Some(out_dir.join(path)), "{:?}", path);
+		}
+
+		#[cfg(unix)]
+		{
+			std::os::unix::fs::symlink(std::env::temp_dir(), out_dir.join("evil")).unwrap();
+			std::os::unix::fs::symlink(std::env::temp_dir().join("evil.lua"), out_dir.join("autorun/evil.lua")).unwrap();
+			assert_eq!(entry_out_path(&out_dir, "evil/x.lua"), None);
+			assert_eq!(entry_out_path(&out_dir, "evil"), None);
+			assert_eq!(entry_out_path(&out_dir, "autorun/evil.lua"), None);
+		}
+	}
+
+	#[cfg(unix)]
+	#[tokio::test]
+	async fn packer_and_unpacker_agree_on_paths() {
+		let root = util::TempDir::new("path-agreement");
+		std::fs::create_dir_all(root.join("myaddon/lua/autorun")).unwrap();
+		std::fs::write(root.join("myaddon/gluapack.json"), r#"{"unique_id": "myaddon"}"#).unwrap();
+		std::fs::write(root.join("myaddon/lua/autorun/sh_a:b.lua"), "print(\"hello\")").unwrap();
+		crate::pack::Packer::pack(root.join("myaddon"), Some(root.join("myaddon-packed")), false, None, 4, false, false, false, true).await.unwrap();
+
+		let verified = Unpacker::verify(root.join("myaddon-packed")).await.unwrap();
+		assert!(verified[0].problems.is_empty(), "{:?}", verified[0].problems.iter().map(|problem| problem.to_string()).collect::<Vec<_>>());
+
+		Unpacker::unpack(root.join("myaddon-packed"), Some(root.join("myaddon-unpacked")), false, false, false, 4, false, true).await.unwrap();
+		assert_eq!(std::fs::read(root.join("myaddon-unpacked/lua/autorun/sh_a:b.lua")).unwrap(), b"print(\"hello\")");
+
+		// A file that would be unpacked to another drive on Windows is refused
+		std::fs::write(root.join("myaddon/lua/autorun/c:evil.lua"), "print(\"hello\")").unwrap();
+		match crate::pack::Packer::pack(root.join("myaddon"), Some(root.join("myaddon-packed")), false, None, 4, false, false, false, true).await {
+			Err(crate::pack::PackingError::UnpackableFile { path, .. }) => assert_eq!(path, "autorun/c:evil.lua"),
+			result => panic!("expected an unpackable file, got {:?}", result.map(|_| ()))
+		}
+	}
+
+	#[tokio::test]
+	async fn arbitrary_bytes_survive_packing() {
+		use sha2::Digest;
+
+		// Every byte value, in an order that's hard to compress
+		let mut large = vec![];
+		for i in 0u32..4096 {
+			large.extend_from_slice(&sha2::Sha256::digest(&i.to_le_bytes()));
+		}
+
+		let files: &[(&str, Vec<u8>)] = &[
+			("autorun/client/cl_a|b.lua", b"a\0b\x01c]]d]==]e\0".to_vec()),
+			("autorun/sh_]]|]==].lua", b"]]]==]\x01\0\0|\x01|".to_vec()),
+			("autorun/server/sv_\x01.lua", b"\0]==]\x01".to_vec()),
+			("autorun/client/cl_large.lua", large.clone()),
+			("autorun/sh_large.lua", large.clone()),
+			("autorun/server/sv_large.lua", large)
+		];
+
+		for compress in [false, true] {
+			let root = util::TempDir::new(if compress { "round-trip-compressed" } else { "round-trip" });
+			std::fs::create_dir_all(root.join("myaddon/lua/autorun/client")).unwrap();
+			std::fs::create_dir_all(root.join("myaddon/lua/autorun/server")).unwrap();
+			std::fs::write(root.join("myaddon/gluapack.json"), format!(r#"{{"unique_id": "myaddon", "compress_networked": {0}, "compress_sv": {0}}}"#, compress)).unwrap();
+			for (path, contents) in files {
+				std::fs::write(root.join("myaddon/lua").join(path), contents).unwrap();
+			}
+
+			let (_, packed_files, _) = crate::pack::Packer::pack(root.join("myaddon"), Some(root.join("myaddon-packed")), false, Some(crate::MIN_LUA_SIZE * 4), 4, false, false, false, true).await.unwrap();
+			if !compress {
+				assert!(packed_files > 2 * (files[3].1.len() / (crate::MIN_LUA_SIZE * 4)), "{} packed files", packed_files);
+			}
+
+			Unpacker::unpack(root.join("myaddon-packed"), Some(root.join("myaddon-unpacked")), false, false, false, 4, false, true).await.unwrap();
+			for (path, contents) in files {
+				assert!(std::fs::read(root.join("myaddon-unpacked/lua").join(path)).unwrap() == *contents, "{:?} changed (compress: {})", path, compress);
+			}
+		}
+	}
+
+	/// Packs an addon with a single clientside file, returning the addon's directory and the pack's directory.
+	async fn packed_addon(name: &str, contents: &[u8], chunk_size: Option<usize>) -> (util::TempDir, PathBuf) {
+		let root = util::TempDir::new(name);
+		std::fs::create_dir_all(root.join("myaddon/lua/autorun/client")).unwrap();
+		std::fs::write(root.join("myaddon/gluapack.json"), r#"{"unique_id": "myaddon"}"#).unwrap();
+		std::fs::write(root.join("myaddon/lua/autorun/client/cl_init.lua"), contents).unwrap();
+		crate::pack::Packer::pack(root.join("myaddon"), Some(root.join("myaddon-packed")), false, chunk_size, 4, false, false, false, true).await.unwrap();
+
+		let pack_dir = root.join("myaddon-packed/lua/gluapack/myaddon");
+		(root, pack_dir)
+	}
+
+	/// Replaces the first occurrence of `from` in a file.
+	fn corrupt(path: &Path, from: &str, to: &str) {
+		let contents = std::fs::read_to_string(path).unwrap();
+		assert!(contents.contains(from), "{:?} isn't in {:?}", from, contents);
+		std::fs::write(path, contents.replacen(from, to, 1)).unwrap();
+	}
+
+	async fn unpack_error(root: &Path) -> UnpackingError {
+		match Unpacker::unpack(root.join("myaddon-packed"), Some(root.join("myaddon-unpacked")), false, false, false, 4, true, true).await {
+			Ok(_) => panic!("unpacking a corrupt pack succeeded"),
+			Err(error) => error
+		}
+	}
+
+	/// The entry [`packed_addon`] writes for `print("hello")`, which follows the header in the only chunk.
+	const ENTRY: &str = "1a|e|autorun/client/cl_init.luaprint(\"hello\")";
+	const ENTRY_PATH: &str = "autorun/client/cl_init.lua";
+
+	fn header_len() -> u64 {
+		PackHeader::new("cl", 1, ENTRY.len() as u64, Compression::None).to_bytes().len() as u64
+	}
+
+	#[tokio::test]
+	async fn truncated_entries_are_reported() {
+		let (root, pack_dir) = packed_addon("truncated-entry", b"print(\"hello\")", None).await;
+		let chunk_file = pack_dir.join("gluapack.1.cl.lua");
+		corrupt(&chunk_file, "lo\")", "");
+
+		match unpack_error(&root).await {
+			UnpackingError::TruncatedEntry { file, offset, path, .. } => {
+				assert_eq!(file, chunk_file);
+				assert_eq!(offset, header_len() + ENTRY.len() as u64 - "lo\")".len() as u64);
+				assert_eq!(path, ENTRY_PATH);
+			},
+			error => panic!("expected a truncated entry, got {}", error)
+		}
+	}
+
+	#[tokio::test]
+	async fn corrupt_lengths_are_reported() {
+		let (root, pack_dir) = packed_addon("corrupt-length", b"print(\"hello\")", None).await;
+		let chunk_file = pack_dir.join("gluapack.1.cl.lua");
+
+		// One byte longer than the file, so reading it runs off the end of the pack
+		corrupt(&chunk_file, "1a|e|", "1a|f|");
+
+		match unpack_error(&root).await {
+			UnpackingError::TruncatedEntry { file, offset, path, .. } => {
+				assert_eq!(file, chunk_file);
+				assert_eq!(offset, header_len() + ENTRY.len() as u64);
+				assert_eq!(path, ENTRY_PATH);
+			},
+			error => panic!("expected a truncated entry, got {}", error)
+		}
+	}
+
+	#[tokio::test]
+	async fn bad_terminators_are_reported() {
+		let (root, pack_dir) = packed_addon("bad-terminator", b"print(\"hello\")", None).await;
+		let chunk_file = pack_dir.join("gluapack.1.cl.lua");
+		corrupt(&chunk_file, "1a|e|", "1a:e|");
+
+		match unpack_error(&root).await {
+			UnpackingError::BadTerminator { file, offset, path, .. } => {
+				assert_eq!(file, chunk_file);
+				assert_eq!(offset, header_len() + "1a:e|".len() as u64);
+				// The path comes after its length
+				assert_eq!(path, "");
+			},
+			error => panic!("expected a bad terminator, got {}", error)
+		}
+	}
+
+	#[tokio::test]
+	async fn length_mismatches_are_reported() {
+		let (root, pack_dir) = packed_addon("length-mismatch", b"print(\"hello\")", None).await;
+		let chunk_file = pack_dir.join("gluapack.1.cl.lua");
+		corrupt(&chunk_file, &format!(" cl 1 {} none", ENTRY.len()), &format!(" cl 1 {} none", ENTRY.len() + 1));
+
+		match unpack_error(&root).await {
+			UnpackingError::LengthMismatch { file, offset, path, expected, found, .. } => {
+				assert_eq!(file, chunk_file);
+				assert_eq!(offset, header_len() + ENTRY.len() as u64);
+				assert_eq!(path, ENTRY_PATH);
+				assert_eq!((expected, found), (ENTRY.len() as u64 + 1, ENTRY.len() as u64));
+			},
+			error => panic!("expected a length mismatch, got {}", error)
+		}
+	}
+
+	#[tokio::test]
+	async fn missing_chunks_are_reported() {
+		let (root, pack_dir) = packed_addon("missing-chunk", "-".repeat(crate::MIN_LUA_SIZE * 3).as_bytes(), Some(crate::MIN_LUA_SIZE)).await;
+		assert!(pack_dir.join("gluapack.3.cl.lua").is_file());
+		std::fs::remove_file(pack_dir.join("gluapack.2.cl.lua")).unwrap();
+
+		match unpack_error(&root).await {
+			UnpackingError::MissingChunk { file, index, .. } => {
+				assert_eq!(file, pack_dir.join("gluapack.2.cl.lua"));
+				assert_eq!(index, 2);
+			},
+			error => panic!("expected a missing chunk, got {}", error)
+		}
+	}
+
+	#[tokio::test]
+	async fn shipped_loader_can_be_parsed() {
+		let (root, _) = packed_addon("loader", b"print(\"hello\")", None).await;
+		let (version, loader_path) = Unpacker::find_loader(&root.join("myaddon-packed/lua"), "myaddon").unwrap();
+		assert_eq!(version, env!("CARGO_PKG_VERSION"));
+
+		let loader = std::fs::read_to_string(&loader_path).unwrap();
+		assert_eq!(parse_loader_format(&loader), Some(PACK_FORMAT_VERSION));
+
+		let entry_files = EntryFiles::parse(&loader).unwrap();
+		assert_eq!(entry_files.cl, [ENTRY_PATH]);
+		assert!(entry_files.sh.is_empty() && entry_files.sv.is_empty());
+
+		corrupt(&loader_path, &format!("GLUAPACK_FORMAT = {}", PACK_FORMAT_VERSION), &format!("GLUAPACK_FORMAT = {}", PACK_FORMAT_VERSION + 1));
+		let verified = Unpacker::verify(root.join("myaddon-packed")).await.unwrap();
+		match &verified[0].problems[..] {
+			[UnpackingError::LoaderFormatMismatch { file, format, .. }] => {
+				assert_eq!(*file, loader_path);
+				assert_eq!(*format, Some(PACK_FORMAT_VERSION + 1));
+			},
+			problems => panic!("expected a loader format mismatch, got {:?}", problems.iter().map(|problem| problem.to_string()).collect::<Vec<_>>())
+		}
+	}
+
+	#[tokio::test]
+	async fn verify_reports_missing_chunks_once() {
+		let root = util::TempDir::new("verify");
+		std::fs::create_dir_all(root.join("myaddon/lua/autorun/client")).unwrap();
+		std::fs::write(root.join("myaddon/gluapack.json"), r#"{"unique_id": "myaddon"}"#).unwrap();
+		std::fs::write(root.join("myaddon/lua/autorun/client/cl_init.lua"), "print(\"hello\")").unwrap();
+		crate::pack::Packer::pack(root.join("myaddon"), Some(root.join("myaddon-packed")), false, None, 4, false, false, false, true).await.unwrap();
+
+		// Found missing both by the manifest, as the last chunk listed in it, and by reading the pack, as the first chunk
+		let pack_dir = root.join("myaddon-packed/lua/gluapack/myaddon");
+		std::fs::rename(pack_dir.join("gluapack.1.cl.lua"), pack_dir.join("gluapack.0.cl.lua")).unwrap();
+
+		let verified = Unpacker::verify(root.join("myaddon-packed")).await.unwrap();
+		let missing = verified[0].problems.iter().filter(|problem| matches!(problem, UnpackingError::MissingChunk { index: 1, .. })).count();
+		assert_eq!(missing, 1, "{:?}", verified[0].problems.iter().map(|problem| problem.to_string()).collect::<Vec<_>>());
+	}
+}
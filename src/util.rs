@@ -1,4 +1,9 @@
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
+
+/// The name of the marker file gluapack writes into every output directory it creates.
+///
+/// gluapack will refuse to delete a non-empty output directory that doesn't contain this file, unless `--force` is used.
+pub const OUTPUT_DIR_MARKER: &str = ".gluapack";
 
 #[macro_export]
 macro_rules! abort {
@@ -47,6 +52,14 @@ macro_rules! error {
 		}
 	};
 
+	($enum:ident::$variant:ident { $($field:ident $(: $value:expr)?),* $(,)? }) => {
+		$enum::$variant {
+			$($field $(: $value)?,)*
+			#[cfg(all(debug_assertions, feature = "nightly"))]
+			backtrace: std::backtrace::Backtrace::force_capture()
+		}
+	};
+
 	($enum:ident::$variant:ident) => {
 		$enum::$variant {
 			#[cfg(all(debug_assertions, feature = "nightly"))]
@@ -71,18 +84,230 @@ pub fn canonicalize(path: &PathBuf) -> PathBuf {
 }
 
 #[inline(always)]
-pub async fn prepare_output_dir(quiet: bool, out_dir: &PathBuf) {
+pub async fn prepare_output_dir(quiet: bool, out_dir: &PathBuf) -> Result<(), std::io::Error> {
+	let result = recreate_output_dir(quiet, out_dir).await;
+
+	quietln!(quiet, "Output Path: {}", canonicalize(out_dir).display());
+
+	result
+}
+
+/// Like [`prepare_output_dir`], but doesn't print the output path.
+pub async fn recreate_output_dir(quiet: bool, out_dir: &Path) -> Result<(), std::io::Error> {
 	if out_dir.is_dir() {
 		quietln!(quiet, "Deleting old output directory...");
-		tokio::fs::remove_dir_all(&out_dir).await.expect("Failed to delete existing output directory");
+		tokio::fs::remove_dir_all(&out_dir).await?;
 	} else if out_dir.is_file() {
 		quietln!(quiet, "Deleting old output directory...");
-		tokio::fs::remove_file(&out_dir).await.expect("Failed to delete existing output directory");
+		tokio::fs::remove_file(&out_dir).await?;
+	}
+
+	tokio::fs::create_dir_all(&out_dir).await?;
+
+	tokio::fs::write(out_dir.join(OUTPUT_DIR_MARKER), "This directory was created by gluapack, and will be deleted by gluapack whenever it writes to it again.\n").await
+}
+
+/// Returns the user's home directory, if it can be determined from the environment.
+pub fn home_dir() -> Option<PathBuf> {
+	std::env::var_os("HOME")
+		.or_else(|| std::env::var_os("USERPROFILE"))
+		.filter(|home| !home.is_empty())
+		.map(PathBuf::from)
+}
+
+/// Like `canonicalize`, but works for paths that don't exist (yet).
+///
+/// `.` and `..` components are resolved lexically, then the longest existing ancestor is canonicalized so that symlinks are followed where possible.
+pub fn canonicalize_lenient(path: &Path) -> PathBuf {
+	let path = if path.is_absolute() {
+		path.to_path_buf()
+	} else {
+		std::env::current_dir().map(|cwd| cwd.join(path)).unwrap_or_else(|_| path.to_path_buf())
+	};
+
+	let mut normalized = PathBuf::new();
+	for component in path.components() {
+		match component {
+			Component::CurDir => {},
+			Component::ParentDir => { normalized.pop(); },
+			component => normalized.push(component)
+		}
+	}
+
+	let mut existing = normalized.as_path();
+	let mut rest = Vec::new();
+	while !existing.exists() {
+		match (existing.parent(), existing.file_name()) {
+			(Some(parent), Some(file_name)) => {
+				rest.push(file_name.to_owned());
+				existing = parent;
+			},
+			_ => return normalized
+		}
 	}
 
-	let result = tokio::fs::create_dir_all(&out_dir).await;
+	let mut canonicalized = canonicalize(&existing.to_path_buf());
+	canonicalized.extend(rest.into_iter().rev());
+	canonicalized
+}
+
+/// Checks that `out_dir` doesn't overlap with the addon, which would have gluapack delete or recurse into the addon itself.
+///
+/// These checks can't be overridden with `--force`.
+pub fn check_output_dir(addon_dir: &Path, out_dir: &Path) -> Result<(), String> {
+	let addon_dir = canonicalize_lenient(addon_dir);
+	let out_dir_canonical = canonicalize_lenient(out_dir);
 
-	quietln!(quiet, "Output Path: {}", canonicalize(&out_dir).display());
+	if out_dir_canonical == addon_dir {
+		return Err("Output directory cannot be the same as the addon directory!".to_string());
+	}
+	if addon_dir.starts_with(&out_dir_canonical) {
+		return Err(format!("Output directory {} contains the addon directory!", out_dir_canonical.display()));
+	}
+	if out_dir_canonical.starts_with(&addon_dir) {
+		return Err(format!("Output directory {} is inside the addon directory!", out_dir_canonical.display()));
+	}
 
-	result.expect("Failed to create output directory");
-}
\ No newline at end of file
+	Ok(())
+}
+
+/// Checks that `out_dir` looks like something gluapack is allowed to delete and recreate.
+///
+/// Returns a description of the problem if the directory doesn't look like one gluapack created.
+pub fn check_output_dir_deletable(out_dir: &Path) -> Result<(), String> {
+	let out_dir_canonical = canonicalize_lenient(out_dir);
+
+	if out_dir_canonical.parent().is_none() {
+		return Err(format!("Output directory {} is the root of a filesystem!", out_dir_canonical.display()));
+	}
+	if let Some(home_dir) = home_dir().map(|home_dir| canonicalize_lenient(&home_dir)) {
+		if home_dir.starts_with(&out_dir_canonical) {
+			return Err(format!("Output directory {} is your home directory, or contains it!", out_dir_canonical.display()));
+		}
+	}
+
+	if out_dir.is_file() {
+		return Err(format!("Output path {} is a file, not a directory!", out_dir_canonical.display()));
+	}
+	if out_dir.is_dir() && !out_dir.join(OUTPUT_DIR_MARKER).is_file() {
+		let is_empty = std::fs::read_dir(out_dir).map(|mut entries| entries.next().is_none()).unwrap_or(false);
+		if !is_empty {
+			return Err(format!("Output directory {} already exists and wasn't created by gluapack!", out_dir_canonical.display()));
+		}
+	}
+
+	Ok(())
+}
+
+/// Whether a packed file's path could escape the `lua/` folder it's unpacked into, on any platform.
+///
+/// The packer refuses these paths, and the unpacker and `verify` reject them. Backslashes are treated as separators and a leading `X:` as a drive,
+/// as they are on Windows, wherever the pack is made. Other characters that Windows can't use in file names can't escape the folder, so they're allowed.
+pub fn escapes_lua_folder(path: &str) -> bool {
+	// Joining a path that starts with a drive onto another path replaces it on Windows
+	let is_drive = |component: &str| matches!(component.as_bytes(), [letter, b':', ..] if letter.is_ascii_alphabetic());
+
+	path.is_empty()
+		|| path.contains('\0')
+		|| path.split(['/', '\\']).any(|component| matches!(component, "" | "." | "..") || is_drive(component))
+}
+
+/// Creates an encoder that compresses everything written to it into the LZMA "alone" format that Garry's Mod's `util.Decompress` reads.
+///
+/// liblzma marks the uncompressed size as unknown, but `util.Decompress` needs to know it, so it must be filled in with [`lzma_set_uncompressed_size`] once the encoder is finished.
+///
+/// The encoder's memory usage is roughly ten times its dictionary size, so the dictionary is no bigger than the data and is capped at `LZMA_MAX_DICT_SIZE`.
+pub fn lzma_encoder<W: std::io::Write>(writer: W, uncompressed_len: u64) -> xz2::write::XzEncoder<W> {
+	const LZMA_MIN_DICT_SIZE: u64 = 4 * 1024;
+	const LZMA_MAX_DICT_SIZE: u64 = 8 * 1024 * 1024;
+
+	let mut options = xz2::stream::LzmaOptions::new_preset(9).expect("Failed to create LZMA options");
+	options.dict_size(uncompressed_len.clamp(LZMA_MIN_DICT_SIZE, LZMA_MAX_DICT_SIZE) as u32);
+
+	let stream = xz2::stream::Stream::new_lzma_encoder(&options).expect("Failed to create LZMA encoder");
+	xz2::write::XzEncoder::new_stream(writer, stream)
+}
+
+/// Fills in the uncompressed size of a file written by an [`lzma_encoder`], then rewinds it.
+pub fn lzma_set_uncompressed_size<F: std::io::Write + std::io::Seek>(file: &mut F, uncompressed_len: u64) -> Result<(), std::io::Error> {
+	use std::io::SeekFrom;
+
+	file.seek(SeekFrom::Start(5))?;
+	file.write_all(&uncompressed_len.to_le_bytes())?;
+	file.seek(SeekFrom::Start(0))?;
+	Ok(())
+}
+
+/// Decompresses data compressed by an [`lzma_encoder`].
+pub fn lzma_decompress(compressed: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+	use std::io::Read;
+
+	let stream = xz2::stream::Stream::new_lzma_decoder(u64::MAX)?;
+
+	let mut bytes = Vec::new();
+	xz2::read::XzDecoder::new_stream(compressed, stream).read_to_end(&mut bytes)?;
+	Ok(bytes)
+}
+
+/// Whether two files have the same contents. A missing `b` is never equal.
+pub fn files_equal(a: &Path, b: &Path) -> Result<bool, std::io::Error> {
+	use std::io::Read;
+
+	let mut b = match std::fs::File::open(b) {
+		Ok(b) => b,
+		Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+		Err(error) => return Err(error)
+	};
+	let mut a = std::fs::File::open(a)?;
+	if a.metadata()?.len() != b.metadata()?.len() {
+		return Ok(false);
+	}
+
+	let mut a_block = vec![0; 64 * 1024];
+	let mut b_block = vec![0; 64 * 1024];
+	loop {
+		let n = a.read(&mut a_block)?;
+		if n == 0 {
+			return Ok(true);
+		}
+		b.read_exact(&mut b_block[..n])?;
+		if a_block[..n] != b_block[..n] {
+			return Ok(false);
+		}
+	}
+}
+
+/// Writes a file, unless it already has these contents, so that its modification time is only changed when it has actually changed.
+pub async fn write_if_changed(path: &Path, contents: &[u8]) -> Result<(), std::io::Error> {
+	if tokio::fs::read(path).await.map(|existing| existing == contents).unwrap_or(false) {
+		return Ok(());
+	}
+	tokio::fs::write(path, contents).await
+}
+
+/// A fresh directory for a test to work in, which is deleted when it's dropped, even if the test fails.
+#[cfg(test)]
+pub struct TempDir(PathBuf);
+#[cfg(test)]
+impl TempDir {
+	pub fn new(name: &str) -> TempDir {
+		let dir = std::env::temp_dir().join(format!("gluapack-test-{}-{}", name, std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		TempDir(canonicalize(&dir))
+	}
+}
+#[cfg(test)]
+impl std::ops::Deref for TempDir {
+	type Target = Path;
+
+	fn deref(&self) -> &Path {
+		&self.0
+	}
+}
+#[cfg(test)]
+impl Drop for TempDir {
+	fn drop(&mut self) {
+		let _ = std::fs::remove_dir_all(&self.0);
+	}
+}
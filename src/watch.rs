@@ -0,0 +1,137 @@
+use crate::{config::Config, pack::Packer};
+use std::{collections::BTreeMap, path::{Path, PathBuf}, time::{Duration, SystemTime}};
+
+/// How often the addon is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long the addon has to stop changing for before it's repacked, so that saving lots of files at once only repacks once.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// The size and modification time of every file being watched.
+type Snapshot = BTreeMap<PathBuf, (u64, Option<SystemTime>)>;
+
+/// Repacks an addon whenever its Lua files or config change.
+///
+/// The addon is polled rather than watched with OS file events. Thanks to the pack cache, a repack only costs as much as what has changed.
+pub struct Watcher {
+	dir: PathBuf,
+	out_dir: Option<PathBuf>,
+	no_copy: bool,
+	chunk_size: Option<usize>,
+	jobs: usize,
+	quiet: bool
+}
+impl Watcher {
+	pub async fn watch(dir: PathBuf, out_dir: Option<PathBuf>, no_copy: bool, chunk_size: Option<usize>, jobs: usize, quiet: bool) -> ! {
+		let watcher = Watcher {
+			dir,
+			out_dir,
+			no_copy,
+			chunk_size,
+			jobs,
+			quiet
+		};
+
+		quietln!(quiet, "Watching {} for changes. Press Ctrl+C to stop.", crate::util::canonicalize(&watcher.dir).display());
+
+		let mut snapshot = watcher.snapshot().await;
+		watcher.repack().await;
+
+		loop {
+			tokio::time::sleep(POLL_INTERVAL).await;
+
+			let mut latest = watcher.snapshot().await;
+			if latest == snapshot {
+				continue;
+			}
+
+			// Wait for the addon to stop changing
+			loop {
+				tokio::time::sleep(DEBOUNCE).await;
+				let settled = watcher.snapshot().await;
+				if settled == latest {
+					break;
+				}
+				latest = settled;
+			}
+
+			quietln!(quiet);
+			quietln!(quiet, "{} changed, repacking...", watcher.describe_changes(&snapshot, &latest));
+
+			snapshot = latest;
+			watcher.repack().await;
+		}
+	}
+
+	/// Packs the addon, reporting any errors without giving up on watching it.
+	async fn repack(&self) {
+		match Packer::pack(self.dir.clone(), self.out_dir.clone(), self.no_copy, self.chunk_size, self.jobs, false, false, false, true).await {
+			Ok((unpacked_files, packed_files, elapsed)) => quietln!(self.quiet, "Packed {} file(s) -> {} files in {:?}", unpacked_files, packed_files, elapsed),
+			Err(error) => eprintln!("ERROR: {}", error)
+		}
+	}
+
+	/// Summarises which files changed between two snapshots, e.g. "lua/autorun/sh_init.lua and 2 other file(s)".
+	fn describe_changes(&self, before: &Snapshot, after: &Snapshot) -> String {
+		let mut changed = before.keys()
+			.filter(|path| after.get(*path) != before.get(*path))
+			.chain(after.keys().filter(|path| !before.contains_key(*path)));
+
+		let first = changed.next().map(|path| path.strip_prefix(&self.dir).unwrap_or(path).to_string_lossy().replace('\\', "/")).unwrap_or_default();
+		match changed.count() {
+			0 => first,
+			others => format!("{} and {} other file(s)", first, others)
+		}
+	}
+
+	async fn snapshot(&self) -> Snapshot {
+		// Files can be deleted while they're being looked at, so errors are ignored; the file just won't be in the snapshot
+		fn walk(snapshot: &mut Snapshot, lua_dir: &Path, dir: &Path) {
+			let read_dir = match dir.read_dir() {
+				Ok(read_dir) => read_dir,
+				Err(_) => return
+			};
+
+			for entry in read_dir.filter_map(|entry| entry.ok()) {
+				let path = entry.path();
+
+				let file_name = entry.file_name();
+				let file_name = file_name.to_string_lossy();
+				if file_name.starts_with('.') {
+					continue;
+				}
+
+				// Don't watch our own output when packing in-place
+				let relative = path.strip_prefix(lua_dir).unwrap_or(&path);
+				if relative == Path::new("gluapack") || (relative.parent() == Some(Path::new("autorun")) && file_name.contains("_gluapack_")) {
+					continue;
+				}
+
+				match entry.file_type() {
+					Ok(file_type) if file_type.is_dir() => walk(snapshot, lua_dir, &path),
+					Ok(_) => if let Ok(metadata) = std::fs::metadata(&path) {
+						snapshot.insert(path, (metadata.len(), metadata.modified().ok()));
+					},
+					Err(_) => {}
+				}
+			}
+		}
+
+		let dir = self.dir.clone();
+		tokio::task::spawn_blocking(move || {
+			let mut snapshot = Snapshot::new();
+
+			// Base configs can be anywhere, so every config in the extends chain is watched
+			for path in Config::FILE_NAMES.iter().flat_map(|file_name| Config::files(&dir.join(file_name))) {
+				if let Ok(metadata) = std::fs::metadata(&path) {
+					snapshot.insert(path, (metadata.len(), metadata.modified().ok()));
+				}
+			}
+
+			let lua_dir = dir.join("lua");
+			walk(&mut snapshot, &lua_dir, &lua_dir);
+
+			snapshot
+		}).await.expect("Failed to join thread")
+	}
+}